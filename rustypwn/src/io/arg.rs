@@ -1,64 +1,345 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::ops::Deref;
 use std::time::Duration;
 use rustypwn_derive::ActionArg;
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
 
 pub type Timeout = Option<Duration>;
 
-// TODO get rid of Vec<u8>, use &[u8] instead
-// but this will require modification on lifetimes (especially in derive impl)
+/// timeouts are written down as seconds, like `1.5`
+mod timeout_secs {
+    use super::Timeout;
+    use serde::de::{self, Deserialize, Deserializer};
+    use serde::Serializer;
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(timeout: &Timeout, serializer: S) -> Result<S::Ok, S::Error> {
+        match timeout {
+            Some(timeout) => serializer.serialize_some(&timeout.as_secs_f64()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Timeout, D::Error> {
+        match Option::<f64>::deserialize(deserializer)? {
+            Some(secs) if secs.is_finite() && secs >= 0.0 => Ok(Some(Duration::from_secs_f64(secs))),
+            Some(secs) => Err(de::Error::custom(format!("invalid timeout {}", secs))),
+            None => Ok(None),
+        }
+    }
+}
+
+fn default_size() -> usize {
+    0x1000
+}
+
+fn default_count() -> usize {
+    1
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// bytes to send, borrowed where possible so sending doesn't copy
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Content<'a>(Cow<'a, [u8]>);
+
+impl<'a> Content<'a> {
+    /// detach from whatever was borrowed
+    pub fn into_owned(self) -> Vec<u8> {
+        self.0.into_owned()
+    }
+}
+
+impl<'a> Deref for Content<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl<'a> AsRef<[u8]> for Content<'a> {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl<'a, T: AsRef<[u8]> + ?Sized> From<&'a T> for Content<'a> {
+    fn from(content: &'a T) -> Self {
+        Content(Cow::Borrowed(content.as_ref()))
+    }
+}
+
+impl<'a> From<Vec<u8>> for Content<'a> {
+    fn from(content: Vec<u8>) -> Self {
+        Content(Cow::Owned(content))
+    }
+}
+
+impl<'a> From<String> for Content<'a> {
+    fn from(content: String) -> Self {
+        Content(Cow::Owned(content.into_bytes()))
+    }
+}
+
+impl<'a, const N: usize> From<[u8; N]> for Content<'a> {
+    fn from(content: [u8; N]) -> Self {
+        Content(Cow::Owned(content.to_vec()))
+    }
+}
+
+/// text where possible, so scripts stay readable, raw bytes otherwise
+impl<'a> Serialize for Content<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(&self.0) {
+            Ok(text) => serializer.serialize_str(text),
+            Err(_) => serializer.serialize_bytes(&self.0),
+        }
+    }
+}
+
+struct ContentVisitor;
+
+impl<'de> Visitor<'de> for ContentVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a string or a list of bytes")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Vec<u8>, E> {
+        Ok(v.as_bytes().to_vec())
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+        Ok(v.to_vec())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+        let mut content = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(b) = seq.next_element::<u8>()? {
+            content.push(b);
+        }
+        Ok(content)
+    }
+}
+
+impl<'de, 'a> Deserialize<'de> for Content<'a> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ContentVisitor).map(Content::from)
+    }
+}
+
+/// pattern matching exactly `bytes`, whatever they are
+pub(crate) fn literal_pattern(bytes: &[u8]) -> String {
+    let mut pattern = "(?-u:".to_string();
+    for b in bytes {
+        pattern.push_str(&format!("\\x{:02x}", b));
+    }
+    pattern.push(')');
+    pattern
+}
+
+/// what `expect` waits for
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Pattern {
+    Regex(String),
+    Literal(Content<'static>),
+}
+
+impl Pattern {
+    pub fn regex<T: ToString>(pattern: T) -> Self {
+        Pattern::Regex(pattern.to_string())
+    }
+
+    pub fn literal<T: Into<Vec<u8>>>(bytes: T) -> Self {
+        Pattern::Literal(bytes.into().into())
+    }
+
+    /// the pattern as a regex, literals matching exactly their bytes
+    pub fn to_regex(&self) -> Cow<'_, str> {
+        match self {
+            Pattern::Regex(pattern) => Cow::Borrowed(pattern),
+            Pattern::Literal(bytes) => Cow::Owned(literal_pattern(bytes)),
+        }
+    }
+}
+
 /// IO action
-#[derive(Debug, Clone, ActionArg)]
-pub enum Action {
+///
+/// Serialized tagged by its lowercase name, like `{"action": "recvline"}`,
+/// with omitted fields taking their defaults.
+///
+/// `ActionMethods` runs the variants directly on tubes, like `p.recvuntil_str("> ")`,
+/// each giving what the tube gives for it.
+#[derive(Debug, Clone, ActionArg, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum Action<'a> {
+    #[method(send_bytes, content)]
     Send {
         #[default = "None"]
+        #[serde(default, with = "timeout_secs", skip_serializing_if = "Option::is_none")]
         timeout: Timeout,
-        #[default = "b\"\".to_vec()"]
-        content: Vec<u8>,
+        #[default = "Content::default()"]
+        #[into]
+        #[serde(default)]
+        content: Content<'a>,
     },
+    #[method(recv_some, size)]
+    #[method(recv_n, size, must = "true")]
     Recv {
         #[default = "None"]
+        #[serde(default, with = "timeout_secs", skip_serializing_if = "Option::is_none")]
         timeout: Timeout,
         #[default = "0x1000"]
+        #[serde(default = "default_size")]
         size: usize,
         #[default = "false"]
+        #[serde(default)]
         /// if we have to receive such size to return
         must: bool,
     },
+    #[method(recv_line)]
     Recvline {
         #[default = "None"]
+        #[serde(default, with = "timeout_secs", skip_serializing_if = "Option::is_none")]
+        timeout: Timeout,
+    },
+    #[method(recv_lines, count)]
+    Recvlines {
+        #[default = "None"]
+        #[serde(default, with = "timeout_secs", skip_serializing_if = "Option::is_none")]
         timeout: Timeout,
+        #[default = "1"]
+        #[serde(default = "default_count")]
+        count: usize,
     },
+    #[method(recvuntil_str, pattern)]
     Recvuntil {
         #[default = "None"]
+        #[serde(default, with = "timeout_secs", skip_serializing_if = "Option::is_none")]
         timeout: Timeout,
         #[default = "\"\".to_string()"]
+        #[into]
+        #[serde(default)]
         pattern: String,
     },
+    /// wait for the first of several patterns
+    #[method(expect_any, patterns)]
+    Expect {
+        #[default = "None"]
+        #[serde(default, with = "timeout_secs", skip_serializing_if = "Option::is_none")]
+        timeout: Timeout,
+        #[default = "vec![]"]
+        #[into]
+        #[serde(default)]
+        patterns: Vec<Pattern>,
+    },
+    #[method(sendline_bytes, content)]
     Sendline {
         #[default = "None"]
+        #[serde(default, with = "timeout_secs", skip_serializing_if = "Option::is_none")]
         timeout: Timeout,
-        #[default = "b\"\".to_vec()"]
-        content: Vec<u8>,
+        #[default = "Content::default()"]
+        #[into]
+        #[serde(default)]
+        content: Content<'a>,
     },
+    #[method(interact)]
     Interactive,
+    #[method(close)]
+    #[method(close_send, stdout = "false")]
     Shutdown {
         #[default = "true"]
+        #[serde(default = "default_true")]
         stdin: bool,
         #[default = "true"]
+        #[serde(default = "default_true")]
         stdout: bool,
     }
 }
 
+impl<'a> Action<'a> {
+    /// timeout of the action, if it has one
+    pub fn timeout_mut(&mut self) -> Option<&mut Timeout> {
+        match self {
+            Action::Send { timeout, .. }
+            | Action::Recv { timeout, .. }
+            | Action::Recvline { timeout }
+            | Action::Recvlines { timeout, .. }
+            | Action::Recvuntil { timeout, .. }
+            | Action::Expect { timeout, .. }
+            | Action::Sendline { timeout, .. } => Some(timeout),
+            Action::Interactive | Action::Shutdown { .. } => None,
+        }
+    }
+
+    /// detach from borrowed content
+    pub fn into_owned(self) -> Action<'static> {
+        match self {
+            Action::Send { timeout, content } => Action::Send {
+                timeout: timeout,
+                content: content.into_owned().into(),
+            },
+            Action::Recv { timeout, size, must } => Action::Recv {
+                timeout: timeout,
+                size: size,
+                must: must,
+            },
+            Action::Recvline { timeout } => Action::Recvline { timeout: timeout },
+            Action::Recvlines { timeout, count } => Action::Recvlines {
+                timeout: timeout,
+                count: count,
+            },
+            Action::Recvuntil { timeout, pattern } => Action::Recvuntil {
+                timeout: timeout,
+                pattern: pattern,
+            },
+            Action::Expect { timeout, patterns } => Action::Expect {
+                timeout: timeout,
+                patterns: patterns,
+            },
+            Action::Sendline { timeout, content } => Action::Sendline {
+                timeout: timeout,
+                content: content.into_owned().into(),
+            },
+            Action::Interactive => Action::Interactive,
+            Action::Shutdown { stdin, stdout } => Action::Shutdown {
+                stdin: stdin,
+                stdout: stdout,
+            },
+        }
+    }
+}
+
 #[test]
 fn test_action() {
+    use std::convert::TryFrom;
     use Action::*;
 
-    match send().into() {
-        Send {
-            timeout,
-            ..
-        } => {
-            assert_eq!(timeout, None);
-        },
-        _ => {}
+    if let Send { timeout, .. } = send().into() {
+        assert_eq!(timeout, None);
     }
+
+    let owned = String::from("hi");
+    let line = sendline().content(&owned);
+    assert_eq!(&*line.content, b"hi");
+    assert_eq!(&*send().content(b"hi").content, b"hi");
+    assert_eq!(&*send().content("hi").content, b"hi");
+    assert_eq!(&*send().content(vec![1u8, 2]).content, [1, 2]);
+    assert_eq!(&*send().content([1u8, 2]).content, [1, 2]);
+
+    let line = self::Sendline::try_from(Action::from(sendline().content("hi"))).unwrap();
+    assert_eq!(&*line.content, b"hi");
+    let e = self::Send::try_from(Action::from(recv())).err().unwrap();
+    assert_eq!(e.kind, crate::io::error::ErrorKind::IncorrectAction);
+
+    assert_eq!(Pattern::regex("a+").to_regex(), "a+");
+    assert_eq!(Pattern::literal("a+").to_regex(), "(?-u:\\x61\\x2b)");
+
 }
@@ -1,55 +1,208 @@
 use regex::bytes::Regex;
-use super::error::Error;
+use regex_syntax::ParserBuilder;
+use super::error::{Error, ErrorKind};
+use std::collections::HashMap;
 use std::mem;
 
+/// compiled patterns kept around at most, so formatted patterns can't pile up
+const MAX_PATTERNS: usize = 64;
+
+/// how far look-arounds like `\b` see past a match, a UTF-8 character
+const LOOKAROUND: usize = 4;
+
+/// a pattern of `get_until`, compiled
+#[derive(Debug)]
+struct Compiled {
+    regex: Regex,
+    /// length of the longest match, `None` without a limit
+    max_len: Option<usize>,
+}
+
 /// buffer to store stream data
+///
+/// Consumed data is only moved out of the way once it makes up half of the
+/// buffer, so taking data costs time proportional to what is taken.
 #[derive(Default, Debug)]
 pub struct Buffer {
     data: Vec<u8>,
+    /// everything before this is consumed already
+    start: usize,
+    /// compiled patterns of `get_until`
+    patterns: HashMap<String, Compiled>,
 }
 
 impl Buffer {
     /// append to the tail of the buffer
     pub fn append(&mut self, data: &mut Vec<u8>) {
-        self.data.append(data);
+        if self.is_empty() {
+            // nothing worth keeping, so just take over the allocation
+            self.start = 0;
+            mem::swap(&mut self.data, data);
+            data.clear();
+        } else {
+            self.data.append(data);
+        }
     }
 
     /// prepend to the buffer
-    pub fn prepend(&mut self, data: &mut Vec<u8>) {
-        let mut data = data.clone();
-        data.append(&mut self.data);
-        self.data = data;
+    pub fn prepend(&mut self, data: &[u8]) {
+        if self.start >= data.len() {
+            let start = self.start - data.len();
+            self.data[start..self.start].copy_from_slice(data);
+            self.start = start;
+        } else {
+            self.data.splice(self.start..self.start, data.iter().cloned());
+        }
+    }
+
+    /// data not consumed yet
+    pub fn data(&self) -> &[u8] {
+        &self.data[self.start..]
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len() - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// drop the first `size` bytes
+    pub fn consume(&mut self, size: usize) {
+        self.start += std::cmp::min(size, self.len());
+        if self.start == self.data.len() {
+            self.data.clear();
+            self.start = 0;
+        } else if self.start > self.data.len() / 2 {
+            self.data.drain(..self.start);
+            self.start = 0;
+        }
+    }
+
+    /// take the first `size` bytes
+    fn take(&mut self, size: usize) -> Vec<u8> {
+        let res = self.data()[..size].to_vec();
+        self.consume(size);
+        res
     }
 
     pub fn get_until(&mut self, pat: &str) -> Result<Option<Vec<u8>>, Error> {
-        let re = Regex::new(pat).map_err(|e| Error::from_source(Box::new(e)))?;
-        match re.find(self.data.as_slice()) {
-            Some(mat) => {
-                let data = self.data.clone();
-                let (l, r) = data.split_at(mat.end());
-                self.data = r.to_vec();
-                Ok(Some(l.to_vec()))
-            },
-            None => Ok(None)
+        Ok(self.get_until_any(&[pat])?.map(|(_index, data)| data))
+    }
+
+    /// take up to the end of the earliest ending match of any of `pats`,
+    /// along with the index of the pattern
+    pub fn get_until_any<T: AsRef<str>>(&mut self, pats: &[T]) -> Result<Option<(usize, Vec<u8>)>, Error> {
+        self.get_until_any_after(pats, 0)
+    }
+
+    /// like `get_until_any`, with the first `scanned` bytes searched before
+    /// without a match
+    ///
+    /// Only the end a match could still reach into is searched again, which
+    /// keeps waiting for a pattern linear. Patterns without a longest match,
+    /// like `a.*b`, are searched from the start.
+    pub fn get_until_any_after<T: AsRef<str>>(
+        &mut self,
+        pats: &[T],
+        scanned: usize,
+    ) -> Result<Option<(usize, Vec<u8>)>, Error> {
+        let mut first: Option<(usize, usize)> = None;
+        for (index, pat) in pats.iter().enumerate() {
+            let compiled = compile(&mut self.patterns, pat.as_ref())?;
+            let from = match compiled.max_len {
+                Some(max_len) => scanned.saturating_sub(max_len + LOOKAROUND),
+                None => 0,
+            };
+            // the whole data is given, so anchors and look-arounds see what's before `from`
+            let end = match compiled.regex.find_at(&self.data[self.start..], from) {
+                Some(mat) => mat.end(),
+                None => continue,
+            };
+            match first {
+                Some((_, first_end)) if first_end <= end => {}
+                _ => first = Some((index, end)),
+            }
+        }
+        Ok(first.map(|(index, end)| (index, self.take(end))))
+    }
+
+    /// whether `get_until_any_after` searches all data again for any of `pats`
+    pub fn rescans<T: AsRef<str>>(&mut self, pats: &[T]) -> Result<bool, Error> {
+        for pat in pats {
+            if compile(&mut self.patterns, pat.as_ref())?.max_len.is_none() {
+                return Ok(true);
+            }
         }
+        Ok(false)
     }
 
     pub fn get(&mut self, size: usize, strict: bool) -> Option<Vec<u8>> {
-        if self.data.len() == 0 {
+        if self.is_empty() {
             return None;
         }
 
-        if self.data.len() >= size {
-            let data = self.data.clone();
-            let (l, r) = data.split_at(size);
-            self.data = r.to_vec();
-            Some(l.to_vec())
+        if self.len() >= size {
+            Some(self.take(size))
         } else if strict {
-            None 
+            None
         } else {
-            let mut res = Vec::new();
-            mem::swap(&mut self.data, &mut res);
-            Some(res)
+            let len = self.len();
+            Some(self.take(len))
         }
     }
 }
+
+/// compiled `pat`, from the cache if it was used before
+fn compile<'a>(patterns: &'a mut HashMap<String, Compiled>, pat: &str) -> Result<&'a Compiled, Error> {
+    if !patterns.contains_key(pat) {
+        let re = Regex::new(pat).map_err(|e| Error::with_source(ErrorKind::InvalidPattern, Box::new(e)))?;
+        // parsed the way `regex::bytes` does
+        let max_len = match ParserBuilder::new().utf8(false).build().parse(pat) {
+            Ok(hir) => hir.properties().maximum_len(),
+            Err(_) => None,
+        };
+        if patterns.len() >= MAX_PATTERNS {
+            patterns.clear();
+        }
+        let compiled = Compiled {
+            regex: re,
+            max_len: max_len,
+        };
+        patterns.insert(pat.to_string(), compiled);
+    }
+    Ok(&patterns[pat])
+}
+
+#[test]
+fn test_buffer() {
+    let mut buf = Buffer::default();
+    buf.append(&mut b"hello world\n> ".to_vec());
+    assert_eq!(buf.get_until("o").unwrap().unwrap(), b"hello");
+    assert_eq!(buf.get(4, true).unwrap(), b" wor");
+    buf.prepend(b"wor");
+    buf.prepend(b"the ");
+    assert_eq!(buf.data(), b"the world\n> ");
+    assert_eq!(buf.get_until("\n").unwrap().unwrap(), b"the world\n");
+    assert!(buf.get_until("$ ").unwrap().is_none());
+    assert_eq!(buf.get_until("(").unwrap_err().kind, ErrorKind::InvalidPattern);
+    assert!(buf.get(3, true).is_none());
+    assert_eq!(buf.get(3, false).unwrap(), b"> ");
+    assert!(buf.get(3, false).is_none());
+
+    buf.append(&mut b"Error: no\nSuccess\n".to_vec());
+    assert_eq!(buf.get_until_any(&["Success\n", "Error"]).unwrap().unwrap(), (1, b"Error".to_vec()));
+    assert!(buf.get_until_any(&["Crash"]).unwrap().is_none());
+
+    // matches reaching back into what was searched before are still found
+    let mut buf = Buffer::default();
+    buf.append(&mut b"xxab".to_vec());
+    assert!(!buf.rescans(&["abc", "\\bab"]).unwrap());
+    assert!(buf.rescans(&["a.*d"]).unwrap());
+    assert!(buf.get_until_any_after(&["abc", "a.*d", "\\bab"], 4).unwrap().is_none());
+    buf.append(&mut b"cd".to_vec());
+    assert_eq!(buf.get_until_any_after(&["abc", "a.*d"], 4).unwrap().unwrap(), (0, b"xxabc".to_vec()));
+    buf.append(&mut b" ab".to_vec());
+    assert_eq!(buf.get_until_any_after(&["\\bab"], 3).unwrap().unwrap(), (0, b"d ab".to_vec()));
+}
@@ -0,0 +1,262 @@
+//! tubes opened from URLs, so targets can come from the command line
+//!
+//! ```ignore
+//! let target = std::env::args().nth(1).unwrap_or("proc:///tmp/vuln".to_string());
+//! let mut p = tube_from_url(&target)?;
+//! ```
+//!
+//! Schemes known from the start:
+//!
+//! - `tcp://host:port`, a `Remote`
+//! - `tls://host:port`, a TLS connection, `?insecure` skips verifying the server,
+//!   with the `tls` feature
+//! - `unix:///path`, a Unix domain socket, on Unix
+//! - `udp://host:port`, each send is a datagram
+//! - `proc:///path/bin?arg=a&arg=b`, a `Process` running `/path/bin a b`, on Unix
+//!
+//! More are added with `register_scheme`.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::UdpSocket;
+#[cfg(feature = "tls")]
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex, OnceLock};
+use super::error::{stream_error, Error};
+#[cfg(unix)]
+use super::process::{Process, ProcessArg};
+use super::remote::{Remote, RemoteArg};
+use super::stream::StreamTube;
+use super::tube::Tube;
+
+pub use url::Url;
+
+/// opens the tube of a URL
+pub type Connector = Arc<dyn Fn(&Url) -> Result<Box<dyn Tube>, Error> + Send + Sync>;
+
+fn url_error(msg: String) -> Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg).into()
+}
+
+fn schemes() -> &'static Mutex<HashMap<String, Connector>> {
+    static SCHEMES: OnceLock<Mutex<HashMap<String, Connector>>> = OnceLock::new();
+    SCHEMES.get_or_init(|| {
+        let mut schemes = HashMap::new();
+        schemes.insert("tcp".to_string(), Arc::new(tcp) as Connector);
+        #[cfg(feature = "tls")]
+        schemes.insert("tls".to_string(), Arc::new(tls) as Connector);
+        #[cfg(unix)]
+        schemes.insert("unix".to_string(), Arc::new(unix) as Connector);
+        schemes.insert("udp".to_string(), Arc::new(udp) as Connector);
+        #[cfg(unix)]
+        schemes.insert("proc".to_string(), Arc::new(process) as Connector);
+        Mutex::new(schemes)
+    })
+}
+
+/// open URLs of `scheme` with `connector`, replacing whatever opened them before
+pub fn register_scheme<F>(scheme: &str, connector: F)
+where
+    F: Fn(&Url) -> Result<Box<dyn Tube>, Error> + Send + Sync + 'static,
+{
+    let mut schemes = schemes().lock().unwrap_or_else(|e| e.into_inner());
+    schemes.insert(scheme.to_lowercase(), Arc::new(connector));
+}
+
+/// open the tube `url` points to, by its scheme
+pub fn tube_from_url(url: &str) -> Result<Box<dyn Tube>, Error> {
+    let url = Url::parse(url).map_err(|e| Error::from_source(Box::new(e)))?;
+    let connector = {
+        let schemes = schemes().lock().unwrap_or_else(|e| e.into_inner());
+        match schemes.get(url.scheme()) {
+            Some(connector) => connector.clone(),
+            None => return Err(url_error(format!("unknown scheme {}", url.scheme()))),
+        }
+    };
+    // the lock is released, so connectors may open other URLs
+    connector(&url)
+}
+
+/// `host:port` of the URL
+fn host_port(url: &Url) -> Result<String, Error> {
+    let host = match url.host_str() {
+        Some(host) if !host.is_empty() => host,
+        _ => return Err(url_error(format!("no host in {}", url))),
+    };
+    match url.port() {
+        Some(port) => Ok(format!("{}:{}", host, port)),
+        None => Err(url_error(format!("no port in {}", url))),
+    }
+}
+
+/// the decoded path of the URL, like `/tmp/my bin` for `/tmp/my%20bin`
+#[cfg(unix)]
+fn path(url: &Url) -> Result<PathBuf, Error> {
+    url.to_file_path().map_err(|_| url_error(format!("no valid path in {}", url)))
+}
+
+/// whether the query has `key`, with or without a value
+#[cfg(feature = "tls")]
+fn has_query(url: &Url, key: &str) -> bool {
+    url.query_pairs().any(|(k, _)| k == key)
+}
+
+fn tcp(url: &Url) -> Result<Box<dyn Tube>, Error> {
+    Ok(Box::new(Remote::try_new(RemoteArg::new(host_port(url)?))?))
+}
+
+#[cfg(feature = "tls")]
+fn tls(url: &Url) -> Result<Box<dyn Tube>, Error> {
+    let insecure = has_query(url, "insecure");
+    let connector = native_tls::TlsConnector::builder()
+        .danger_accept_invalid_certs(insecure)
+        .danger_accept_invalid_hostnames(insecure)
+        .build()
+        .map_err(|e| Error::from_source(Box::new(e)))?;
+    let stream = TcpStream::connect(host_port(url)?).map_err(stream_error)?;
+    let host = url.host_str().unwrap_or_default().trim_start_matches('[').trim_end_matches(']');
+    let stream = connector.connect(host, stream).map_err(|e| match e {
+        native_tls::HandshakeError::Failure(e) => Error::from_source(Box::new(e)),
+        native_tls::HandshakeError::WouldBlock(_) => Error::timeout(),
+    })?;
+    let tube = StreamTube::new(stream).with_read_timeout(|s, timeout| s.get_ref().set_read_timeout(timeout));
+    Ok(Box::new(tube))
+}
+
+#[cfg(unix)]
+fn unix(url: &Url) -> Result<Box<dyn Tube>, Error> {
+    let stream = UnixStream::connect(path(url)?).map_err(stream_error)?;
+    let tube = StreamTube::new(stream).with_read_timeout(|s, timeout| s.set_read_timeout(timeout));
+    Ok(Box::new(tube))
+}
+
+/// connected UDP socket as a stream, every write goes out as one datagram
+struct Datagram {
+    socket: UdpSocket,
+    /// what is left of the last datagram after the reads asking for less
+    rest: Vec<u8>,
+}
+
+impl Read for Datagram {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // an empty datagram is no data, not the end of the stream
+        while self.rest.is_empty() {
+            let mut datagram = vec![0u8; 0x10000];
+            let n = self.socket.recv(&mut datagram)?;
+            datagram.truncate(n);
+            self.rest = datagram;
+        }
+        let n = std::cmp::min(buf.len(), self.rest.len());
+        buf[..n].copy_from_slice(&self.rest[..n]);
+        self.rest.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for Datagram {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.send(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn udp(url: &Url) -> Result<Box<dyn Tube>, Error> {
+    let addr = host_port(url)?;
+    let local_addr = if addr.starts_with('[') { "[::]:0" } else { "0.0.0.0:0" };
+    let socket = UdpSocket::bind(local_addr)?;
+    socket.connect(&addr).map_err(stream_error)?;
+    let datagram = Datagram {
+        socket: socket,
+        rest: vec![],
+    };
+    let tube = StreamTube::new(datagram).with_read_timeout(|s, timeout| s.socket.set_read_timeout(timeout));
+    Ok(Box::new(tube))
+}
+
+#[cfg(unix)]
+fn process(url: &Url) -> Result<Box<dyn Tube>, Error> {
+    let args = url
+        .query_pairs()
+        .filter(|(key, _)| key == "arg")
+        .map(|(_, value)| value.into_owned())
+        .collect::<Vec<_>>();
+    let path = path(url)?;
+    let path = match path.to_str() {
+        Some(path) => path,
+        None => return Err(url_error(format!("path of {} is no valid UTF-8", url))),
+    };
+    let mut argv = vec![path];
+    argv.extend(args.iter().map(|arg| arg.as_str()));
+    Ok(Box::new(Process::try_new(ProcessArg::default().argv(&argv))?))
+}
+
+#[cfg(unix)]
+#[test]
+fn test_tube_from_url() {
+    use super::arg::ActionMethods;
+    use super::stream::Duplex;
+    use std::io::Cursor;
+    use std::net::TcpListener;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(b"tcp\n").unwrap();
+    });
+    let mut p = tube_from_url(&format!("tcp://{}", addr)).unwrap();
+    assert_eq!(p.recv_line().unwrap(), b"tcp\n");
+    server.join().unwrap();
+
+    let path = std::env::temp_dir().join(format!("rustypwn {}.sock", std::process::id()));
+    let listener = UnixListener::bind(&path).unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(b"unix\n").unwrap();
+    });
+    let url = format!("unix://{}", path.display()).replace(' ', "%20");
+    let mut p = tube_from_url(&url).unwrap();
+    assert_eq!(p.recv_line().unwrap(), b"unix\n");
+    server.join().unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut p = tube_from_url(&format!("udp://{}", socket.local_addr().unwrap())).unwrap();
+    p.send_bytes("ping").unwrap();
+    let mut buf = [0u8; 16];
+    let (n, peer) = socket.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"ping");
+    socket.send_to(b"pong", peer).unwrap();
+    assert_eq!(p.recv_n(4).unwrap(), b"pong");
+    // a datagram bigger than asked for is kept for the next reads, empty ones are skipped
+    socket.send_to(b"AAAABBBB", peer).unwrap();
+    socket.send_to(b"", peer).unwrap();
+    socket.send_to(b"CC", peer).unwrap();
+    assert_eq!(p.recv_n(4).unwrap(), b"AAAA");
+    assert_eq!(p.recv_n(6).unwrap(), b"BBBBCC");
+
+    let mut p = tube_from_url("proc:///bin/ec%68o?arg=hello%20there&arg=again").unwrap();
+    assert_eq!(p.recv_line().unwrap(), b"hello there again\n");
+
+    register_scheme("mem", |url: &Url| {
+        let stream = Duplex::new(Cursor::new(url.path().as_bytes().to_vec()), vec![]);
+        Ok(Box::new(StreamTube::new(stream)) as Box<dyn Tube>)
+    });
+    let mut p = tube_from_url("mem:hello").unwrap();
+    assert_eq!(p.recv_n(5).unwrap(), b"hello");
+
+    assert!(tube_from_url("nope://localhost:1").is_err());
+    assert!(tube_from_url("tcp://localhost").is_err());
+}
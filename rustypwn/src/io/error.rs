@@ -1,3 +1,4 @@
+use super::arg::Action;
 use std::fmt;
 use subprocess::PopenError;
 use subprocess::ExitStatus;
@@ -13,6 +14,16 @@ pub enum ErrorKind {
     Popen,
     /// get terminates when trying to send or recv
     UnexpectedTerminate(ExitStatus),
+    /// the other side closed the connection
+    Eof,
+    /// a pattern is no valid regex
+    InvalidPattern,
+    /// nobody listens at the remote address
+    ConnectionRefused,
+    /// the connection was reset or aborted
+    ConnectionReset,
+    /// the remote address could not be resolved
+    Resolve,
     /// other error source
     Source,
 }
@@ -24,6 +35,11 @@ impl fmt::Display for ErrorKind {
             ErrorKind::Timeout => write!(f, "Timeout"),
             ErrorKind::Source => write!(f, "Error from another source"),
             ErrorKind::Popen => write!(f, "Process open error"),
+            ErrorKind::Eof => write!(f, "Connection closed"),
+            ErrorKind::InvalidPattern => write!(f, "Invalid pattern"),
+            ErrorKind::ConnectionRefused => write!(f, "Connection refused"),
+            ErrorKind::ConnectionReset => write!(f, "Connection reset"),
+            ErrorKind::Resolve => write!(f, "Address resolution failed"),
             ErrorKind::UnexpectedTerminate(status) => {
                 let _ = write!(f, "process terminates ");
                 match status {
@@ -40,33 +56,59 @@ impl fmt::Display for ErrorKind {
 #[derive(Debug)]
 pub struct Error {
     pub kind: ErrorKind,
-    pub source: Option<Box<dyn std::error::Error + 'static>>,
+    pub source: Option<Box<dyn std::error::Error + Send + Sync + 'static>>,
+    /// copy of the data received but not consumed when a receive gave up
+    pub pending: Option<Vec<u8>>,
+    /// the action which failed
+    pub action: Option<Action<'static>>,
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let res = self.kind.fmt(f);
-        match &self.source {
-            Some(source) => source.fmt(f),
-            _ => res,
+        self.kind.fmt(f)?;
+        if let Some(source) = &self.source {
+            write!(f, ": {}", source)?;
+        }
+        if let Some(action) = &self.action {
+            write!(f, " in {:?}", action)?;
+        }
+        if let Some(pending) = &self.pending {
+            write!(f, ", pending {:?}", String::from_utf8_lossy(pending))?;
         }
+        Ok(())
     }
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.source {
+            Some(source) => Some(&**source),
+            None => None,
+        }
+    }
+}
 
 impl Error {
     pub fn from_kind(kind: ErrorKind) -> Self {
         Error {
             kind: kind,
             source: None,
+            pending: None,
+            action: None,
         }
     }
 
-    pub fn from_source(source: Box<dyn std::error::Error + 'static>) -> Self {
+    pub fn from_source(source: Box<dyn std::error::Error + Send + Sync + 'static>) -> Self {
+        Self::with_source(ErrorKind::Source, source)
+    }
+
+    /// error of a specific kind caused by `source`
+    pub fn with_source(kind: ErrorKind, source: Box<dyn std::error::Error + Send + Sync + 'static>) -> Self {
         Error {
-            kind: ErrorKind::Source,
-            source: Some(source)
+            kind: kind,
+            source: Some(source),
+            pending: None,
+            action: None,
         }
     }
 
@@ -75,8 +117,51 @@ impl Error {
         Error {
             kind: ErrorKind::Timeout,
             source: None,
+            pending: None,
+            action: None,
         }
     }
+
+    pub fn eof() -> Self {
+        Error {
+            kind: ErrorKind::Eof,
+            source: None,
+            pending: None,
+            action: None,
+        }
+    }
+
+    /// whether the other side is gone, by closing, resetting or exiting
+    pub fn is_closed(&self) -> bool {
+        matches!(self.kind, ErrorKind::Eof | ErrorKind::ConnectionReset | ErrorKind::UnexpectedTerminate(_))
+    }
+
+    /// whether the other side didn't deliver, rather than something going wrong locally
+    pub fn is_incomplete(&self) -> bool {
+        self.kind == ErrorKind::Timeout || self.is_closed()
+    }
+
+    /// record what was pending and which action failed, for timeouts and terminations
+    pub fn with_pending(mut self, pending: &[u8], action: Action) -> Self {
+        if self.is_incomplete() {
+            self.pending = Some(pending.to_vec());
+            self.action = Some(action.into_owned());
+        }
+        self
+    }
+}
+
+/// map stream errors, so timeouts and closed connections get their own kinds
+pub(crate) fn stream_error(e: std::io::Error) -> Error {
+    match e.kind() {
+        std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => Error::timeout(),
+        std::io::ErrorKind::BrokenPipe => Error::with_source(ErrorKind::Eof, Box::new(e)),
+        std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::ConnectionAborted => {
+            Error::with_source(ErrorKind::ConnectionReset, Box::new(e))
+        }
+        std::io::ErrorKind::ConnectionRefused => Error::with_source(ErrorKind::ConnectionRefused, Box::new(e)),
+        _ => e.into(),
+    }
 }
 
 macro_rules! impl_from_source {
@@ -89,5 +174,39 @@ macro_rules! impl_from_source {
     }
 }
 
-impl_from_source!(PopenError);
 impl_from_source!(std::io::Error);
+
+impl From<PopenError> for Error {
+    fn from(source: PopenError) -> Self {
+        Self::with_source(ErrorKind::Popen, Box::new(source))
+    }
+}
+
+impl From<Error> for std::io::Error {
+    fn from(error: Error) -> Self {
+        let kind = match error.kind {
+            ErrorKind::Timeout => std::io::ErrorKind::TimedOut,
+            ErrorKind::Eof | ErrorKind::UnexpectedTerminate(_) => std::io::ErrorKind::BrokenPipe,
+            ErrorKind::InvalidPattern => std::io::ErrorKind::InvalidInput,
+            ErrorKind::ConnectionRefused => std::io::ErrorKind::ConnectionRefused,
+            ErrorKind::ConnectionReset => std::io::ErrorKind::ConnectionReset,
+            _ => std::io::ErrorKind::Other,
+        };
+        std::io::Error::new(kind, error)
+    }
+}
+
+#[test]
+fn test_error() {
+    use std::error::Error as StdError;
+
+    let e = Error::with_source(
+        ErrorKind::ConnectionRefused,
+        Box::new(std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "refused")),
+    );
+    assert_eq!(e.to_string(), "Connection refused: refused");
+    assert_eq!(e.source().unwrap().to_string(), "refused");
+    assert!(!e.is_closed());
+    assert!(Error::from_kind(ErrorKind::ConnectionReset).is_closed());
+    assert!(Error::timeout().source().is_none());
+}
@@ -2,5 +2,17 @@ pub mod error;
 pub mod arg;
 pub mod buffer;
 pub mod tube;
+mod poll;
+mod telnet;
+#[cfg(unix)]
 pub mod process;
 pub mod remote;
+pub mod stream;
+pub mod reconnect;
+pub mod proxy;
+pub mod relay;
+pub mod std_io;
+#[cfg(unix)]
+pub mod stdio;
+pub mod script;
+pub mod connect;
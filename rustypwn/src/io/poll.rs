@@ -0,0 +1,90 @@
+//! waiting on file descriptors with deadlines, so IO never has to spin
+//!
+//! Deadlines work everywhere, waiting on descriptors only on Unix.
+
+use super::arg::Timeout;
+#[cfg(unix)]
+use std::io;
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
+
+/// point in time when waiting gives up, `None` waits forever
+pub(crate) type Deadline = Option<Instant>;
+
+pub(crate) fn deadline(timeout: Timeout) -> Deadline {
+    timeout.map(|timeout| Instant::now() + timeout)
+}
+
+/// time left until the deadline, zero once it has passed
+pub(crate) fn remaining(deadline: Deadline) -> Timeout {
+    deadline.map(|deadline| {
+        let now = Instant::now();
+        if deadline > now {
+            deadline - now
+        } else {
+            Duration::from_secs(0)
+        }
+    })
+}
+
+#[cfg(unix)]
+pub(crate) fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+pub(crate) fn pollfd(fd: RawFd, events: libc::c_short) -> libc::pollfd {
+    libc::pollfd {
+        fd: fd,
+        events: events,
+        revents: 0,
+    }
+}
+
+/// wait until any of `fds` is ready, returns false when the deadline passed first
+///
+/// Hang ups and errors count as ready, so the following read or write reports them.
+#[cfg(unix)]
+pub(crate) fn poll(fds: &mut [libc::pollfd], deadline: Deadline) -> io::Result<bool> {
+    loop {
+        let timeout = match remaining(deadline) {
+            // round up, so we never wake up right before the deadline
+            Some(left) => {
+                let millis = left.as_millis() + u128::from(left.subsec_nanos() % 1_000_000).div_ceil(1_000_000);
+                if millis > libc::c_int::MAX as u128 {
+                    libc::c_int::MAX
+                } else {
+                    millis as libc::c_int
+                }
+            }
+            None => -1,
+        };
+
+        let res = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+
+        if res > 0 {
+            return Ok(true);
+        }
+
+        if remaining(deadline) == Some(Duration::from_secs(0)) {
+            return Ok(false);
+        }
+    }
+}
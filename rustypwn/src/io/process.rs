@@ -1,16 +1,22 @@
 use super::arg::{Action, Timeout};
 use super::buffer::Buffer;
 use super::error::{Error, ErrorKind};
-use super::tube::{Tube, TubeInternal};
+use super::poll::{self, Deadline};
+use super::tube::{Newline, Tube, TubeInternal, TubeRecv, TubeSend};
 use rustypwn_derive::action;
 use std::ffi::OsString;
+use std::fs::File;
+use std::io::{Read, Write};
 use std::ops::Drop;
-use std::time::SystemTime;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
 use subprocess::{Popen, PopenConfig, Redirection};
 
 pub struct ProcessArg<'a> {
     argv: &'a [&'a str],
     env: Option<Vec<(&'a str, &'a str)>>,
+    newline: &'a [u8],
 }
 
 impl<'a> Default for ProcessArg<'a> {
@@ -18,6 +24,7 @@ impl<'a> Default for ProcessArg<'a> {
         ProcessArg {
             argv: &[""],
             env: None,
+            newline: b"\n",
         }
     }
 }
@@ -32,24 +39,98 @@ impl<'a> ProcessArg<'a> {
         self.env = Some(environ);
         self
     }
+
+    /// line terminator of `sendline` and `recvline`, `\n` by default
+    pub fn newline(mut self, new_newline: &'a [u8]) -> Self {
+        self.newline = new_newline;
+        self
+    }
 }
 
-pub struct Process {
+/// how long a process which closed its output gets to report the exit status
+const EXIT_GRACE: Duration = Duration::from_millis(50);
+
+/// child process shared by the halves, terminated once all of them are gone
+struct Child(Popen);
+
+impl Drop for Child {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.terminate() {
+            log::warn!("unable to terminate process: {}", e);
+        }
+    }
+}
+
+type SharedChild = Arc<Mutex<Child>>;
+
+fn lock(p: &SharedChild) -> MutexGuard<'_, Child> {
+    // a panic while holding the lock leaves the process itself intact
+    p.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// error for a pipe closed by the process, usually because it exited
+fn closed_error(p: &SharedChild) -> Error {
+    match lock(p).0.wait_timeout(EXIT_GRACE) {
+        Ok(Some(exit)) => Error::from_kind(ErrorKind::UnexpectedTerminate(exit)),
+        _ => Error::eof(),
+    }
+}
+
+fn nonblocking_pipe(pipe: Option<File>) -> Result<Option<File>, Error> {
+    if let Some(ref pipe) = pipe {
+        poll::set_nonblocking(pipe.as_raw_fd())?;
+    }
+    Ok(pipe)
+}
+
+/// read what the pipe has now, up to about `limit` bytes, the pipe is dropped on EOF
+fn read_pipe(pipe: &mut Option<File>, v: &mut Vec<u8>, limit: usize) -> Result<(), Error> {
+    let mut chunk = [0u8; 0x1000];
+    while v.len() < limit {
+        let res = match pipe {
+            Some(ref mut pipe) => pipe.read(&mut chunk),
+            None => return Ok(()),
+        };
+        match res {
+            Ok(0) => *pipe = None,
+            Ok(n) => v.extend_from_slice(&chunk[..n]),
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// receiving half of a process, reads both stdout and stderr
+pub struct ProcessReader {
     buf: Buffer,
-    p: Popen,
+    stdout: Option<File>,
+    stderr: Option<File>,
+    newline: Vec<u8>,
+    p: SharedChild,
+}
+
+/// sending half of a process, writes to stdin
+pub struct ProcessWriter {
+    stdin: Option<File>,
+    newline: Vec<u8>,
+    p: SharedChild,
+}
+
+pub struct Process {
+    reader: ProcessReader,
+    writer: ProcessWriter,
 }
 
 impl Process {
     pub fn try_new<'a>(arg: ProcessArg<'a>) -> Result<Self, Error> {
-        let env = match arg.env {
-            Some(env) => Some(
-                env.iter()
-                    .map(|each| (each.0.to_string().into(), each.1.to_string().into()))
-                    .collect::<Vec<(OsString, OsString)>>(),
-            ),
-            None => None,
-        };
-        let p = Popen::create(
+        let env = arg.env.map(|env| {
+            env.iter()
+                .map(|each| (each.0.to_string().into(), each.1.to_string().into()))
+                .collect::<Vec<(OsString, OsString)>>()
+        });
+        let mut p = Popen::create(
             arg.argv,
             PopenConfig {
                 stdin: Redirection::Pipe,
@@ -60,85 +141,98 @@ impl Process {
                 ..Default::default()
             },
         )?;
-        let buf = Buffer::default();
-        Ok(Self { buf: buf, p: p })
-    }
-}
-
-impl TubeInternal for Process {
-    fn mut_buffer(&mut self) -> &mut Buffer {
-        &mut self.buf
+        // the pipes are driven by poll, so none of them may block
+        let stdin = nonblocking_pipe(p.stdin.take())?;
+        let stdout = nonblocking_pipe(p.stdout.take())?;
+        let stderr = nonblocking_pipe(p.stderr.take())?;
+        let p = Arc::new(Mutex::new(Child(p)));
+        Ok(Self {
+            reader: ProcessReader {
+                buf: Buffer::default(),
+                stdout: stdout,
+                stderr: stderr,
+                newline: arg.newline.to_vec(),
+                p: p.clone(),
+            },
+            writer: ProcessWriter {
+                stdin: stdin,
+                newline: arg.newline.to_vec(),
+                p: p,
+            },
+        })
     }
 
-    fn buffer(&self) -> &Buffer {
-        &self.buf
+    /// split into halves which can be used from different threads,
+    /// the process is terminated once both halves are dropped
+    pub fn split(self) -> (ProcessReader, ProcessWriter) {
+        (self.reader, self.writer)
     }
+}
 
-    #[action(stdin, stdout)]
-    fn shutdown(&mut self, action: Action) -> Result<(), Error> {
-        if stdin {
-            self.p.shutdown_stdin()?;
-        }
-
-        if stdout {
-            self.p.shutdown_stdout()?;
-        }
-
-        Ok(())
+impl ProcessReader {
+    fn pollfds(&self) -> Vec<libc::pollfd> {
+        self.stdout
+            .iter()
+            .chain(self.stderr.iter())
+            .map(|pipe| poll::pollfd(pipe.as_raw_fd(), libc::POLLIN))
+            .collect()
     }
 
-    #[action(timeout, content)]
-    fn send(&mut self, action: Action) -> Result<(), Error> {
-        let _ = timeout;
-
-        if let Some(exit) = self.p.poll() {
-            return Err(Error::from_kind(ErrorKind::UnexpectedTerminate(exit)));
-        }
-
-        let input = Some(content.as_ref());
-        let (out, err) = self.p.communicate_bytes(input)?;
+    /// read whatever stdout and stderr have now, returns false once both are closed
+    fn read_available(&mut self, v: &mut Vec<u8>, limit: usize) -> Result<bool, Error> {
         // the time seq of stdout and stderr is not known naturally
         // so we just arrange them in this way
-        if let Some(mut out) = out {
-            self.mut_buffer().append(&mut out);
-        }
-
-        if let Some(mut err) = err {
-            self.mut_buffer().append(&mut err);
-        }
-
-        Ok(())
+        read_pipe(&mut self.stdout, v, limit)?;
+        read_pipe(&mut self.stderr, v, limit)?;
+        Ok(self.stdout.is_some() || self.stderr.is_some())
     }
 
-    fn recv_once(&mut self, size: usize, timeout: Timeout) -> Result<Vec<u8>, Error> {
+    /// wait for some output, at most until the deadline
+    fn recv_before(&mut self, size: usize, deadline: Deadline) -> Result<Vec<u8>, Error> {
         let mut v = vec![];
-        let now = SystemTime::now();
         loop {
-            let (out, err) = self.p.communicate_bytes(None)?;
-            if let Some(mut out) = out {
-                v.append(&mut out);
+            let open = self.read_available(&mut v, size)?;
+            if !v.is_empty() {
+                return Ok(v);
             }
 
-            if let Some(mut err) = err {
-                v.append(&mut err);
-            }
-            if v.len() >= size {
-                break;
+            if !open {
+                return Err(closed_error(&self.p));
             }
 
-            if let Some(duration) = timeout {
-                if now.elapsed().unwrap() >= duration {
-                    break;
-                }
+            if !poll::poll(&mut self.pollfds(), deadline)? {
+                return Err(Error::timeout());
             }
         }
+    }
+}
 
-        Ok(v)
+impl Newline for ProcessReader {
+    fn newline(&self) -> &[u8] {
+        &self.newline
+    }
+}
+
+impl TubeRecv for ProcessReader {
+    fn mut_buffer(&mut self) -> &mut Buffer {
+        &mut self.buf
+    }
+
+    fn buffer(&self) -> &Buffer {
+        &self.buf
+    }
+
+    fn recv_once(&mut self, size: usize, timeout: Timeout) -> Result<Vec<u8>, Error> {
+        self.recv_before(size, poll::deadline(timeout))
+    }
+
+    fn read_fds(&self) -> Option<Vec<RawFd>> {
+        Some(self.pollfds().iter().map(|pollfd| pollfd.fd).collect())
     }
 
     #[action(timeout, size, must)]
     fn recv(&mut self, action: Action) -> Result<Vec<u8>, Error> {
-        let now = SystemTime::now();
+        let deadline = poll::deadline(timeout);
 
         loop {
             let res = self.mut_buffer().get(size, must);
@@ -146,35 +240,135 @@ impl TubeInternal for Process {
                 return Ok(res);
             }
 
-            if let Some(exit) = self.p.poll() {
-                return Err(Error::from_kind(ErrorKind::UnexpectedTerminate(exit)));
+            match self.recv_before(size, deadline) {
+                Ok(mut res) => self.mut_buffer().append(&mut res),
+                Err(e) => {
+                    let action = Action::Recv {
+                        timeout: timeout,
+                        size: size,
+                        must: must,
+                    };
+                    return Err(e.with_pending(self.buffer().data(), action));
+                }
             }
+        }
+    }
+}
+
+impl ProcessWriter {
+    /// write everything before the deadline
+    ///
+    /// The output of `reader` is drained into its buffer meanwhile, so a process
+    /// blocked on writing its output doesn't leave both sides waiting forever.
+    fn write_before(
+        &mut self,
+        content: &[u8],
+        deadline: Deadline,
+        mut reader: Option<&mut ProcessReader>,
+    ) -> Result<(), Error> {
+        let mut written = 0;
+        while written < content.len() {
+            let stdin = match self.stdin {
+                Some(ref mut stdin) => stdin,
+                None => return Err(Error::eof()),
+            };
 
-            let (out, err) = self.p.communicate_bytes(None)?;
-            if let Some(mut out) = out {
-                self.mut_buffer().append(&mut out);
+            let mut fds = vec![poll::pollfd(stdin.as_raw_fd(), libc::POLLOUT)];
+            if let Some(ref reader) = reader {
+                fds.extend(reader.pollfds());
             }
-            if let Some(mut err) = err {
-                self.mut_buffer().append(&mut err);
+            if !poll::poll(&mut fds, deadline)? {
+                return Err(Error::timeout());
             }
 
-            if let Some(timeout) = timeout {
-                match now.elapsed() {
-                    Ok(elapsed) => {
-                        if elapsed >= timeout {
-                            return Err(Error::timeout());
-                        }
-                    }
-                    _ => panic!("get time error, internal bug"),
+            if let Some(ref mut reader) = reader {
+                let mut out = vec![];
+                reader.read_available(&mut out, 0x1000)?;
+                reader.buf.append(&mut out);
+            }
+
+            if fds[0].revents == 0 {
+                continue;
+            }
+
+            match stdin.write(&content[written..]) {
+                Ok(n) => written += n,
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(ref e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                    return Err(closed_error(&self.p));
                 }
+                Err(e) => return Err(e.into()),
             }
         }
+
+        Ok(())
     }
 }
 
-impl Drop for Process {
-    fn drop(&mut self) {
-        self.p.terminate().expect("unable to terminate process")
+impl Newline for ProcessWriter {
+    fn newline(&self) -> &[u8] {
+        &self.newline
+    }
+}
+
+impl TubeSend for ProcessWriter {
+    #[action(timeout, content)]
+    fn send(&mut self, action: Action) -> Result<(), Error> {
+        self.write_before(&content, poll::deadline(timeout), None)
+    }
+}
+
+impl Newline for Process {
+    fn newline(&self) -> &[u8] {
+        self.reader.newline()
+    }
+}
+
+impl TubeRecv for Process {
+    fn mut_buffer(&mut self) -> &mut Buffer {
+        self.reader.mut_buffer()
+    }
+
+    fn buffer(&self) -> &Buffer {
+        self.reader.buffer()
+    }
+
+    fn recv_once(&mut self, size: usize, timeout: Timeout) -> Result<Vec<u8>, Error> {
+        self.reader.recv_once(size, timeout)
+    }
+
+    fn read_fds(&self) -> Option<Vec<RawFd>> {
+        self.reader.read_fds()
+    }
+
+    fn recv(&mut self, action: Action) -> Result<Vec<u8>, Error> {
+        self.reader.recv(action)
+    }
+}
+
+impl TubeSend for Process {
+    #[action(timeout, content)]
+    fn send(&mut self, action: Action) -> Result<(), Error> {
+        self.writer
+            .write_before(&content, poll::deadline(timeout), Some(&mut self.reader))
+    }
+}
+
+impl TubeInternal for Process {
+    #[action(stdin, stdout)]
+    fn shutdown(&mut self, action: Action) -> Result<(), Error> {
+        // closing the pipes is what tells the process
+        if stdin {
+            self.writer.stdin = None;
+        }
+
+        if stdout {
+            self.reader.stdout = None;
+            self.reader.stderr = None;
+        }
+
+        Ok(())
     }
 }
 
@@ -187,20 +381,117 @@ fn popen_test_unix() {
     use std::time::*;
 
     let mut p = Process::try_new(ProcessArg::default().argv(&["cat"])).unwrap();
-    p.send(send().content(b"123".to_vec()).into()).unwrap();
+    p.send(send().content(b"123").into()).unwrap();
     let res = p.recv(recv().size(20).into()).unwrap();
     assert!(res == b"123");
 
     let mut p = Process::try_new(ProcessArg::default().argv(&["cat"])).unwrap();
     let now = SystemTime::now();
-    assert!(
-        p.recv(recv().size(20).timeout(Some(Duration::from_secs(1))).into())
-            .is_err()
-            == true
-    );
+    assert!(p.recv(recv().size(20).timeout(Some(Duration::from_secs(1))).into()).is_err());
     let elapsed = now.elapsed().unwrap();
     assert!(Duration::from_secs(2) >= elapsed);
     assert!(Duration::from_secs(1) <= elapsed);
     let p = Process::try_new(ProcessArg::default().argv(&["bash"])).unwrap();
     drop(p);
 }
+
+#[cfg(unix)]
+#[test]
+fn split_test_unix() {
+    use super::arg::*;
+    use std::thread;
+
+    let p = Process::try_new(ProcessArg::default().argv(&["cat"])).unwrap();
+    let (mut reader, mut writer) = p.split();
+    let recving = thread::spawn(move || reader.recvline(recvline().into()).unwrap());
+    writer.sendline(sendline().content(b"hello").into()).unwrap();
+    drop(writer);
+    assert_eq!(&recving.join().unwrap(), b"hello\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn pipe_full_test_unix() {
+    use super::arg::*;
+
+    // way more than a pipe holds, cat blocks on its output until we read
+    let content = vec![b'a'; 0x100000];
+    let mut p = Process::try_new(ProcessArg::default().argv(&["cat"])).unwrap();
+    p.send(send().content(&content).into()).unwrap();
+    let res = p.recv(recv().size(content.len()).must(true).into()).unwrap();
+    assert_eq!(res, content);
+}
+
+#[cfg(unix)]
+#[test]
+fn newline_test_unix() {
+    use super::arg::*;
+
+    let arg = ProcessArg::default().argv(&["cat"]).newline(b"\r\n");
+    let mut p = Process::try_new(arg).unwrap();
+    p.sendline(sendline().content(b"a\nb").into()).unwrap();
+    p.sendline(sendline().content(b"c").into()).unwrap();
+    let lines = p.recvlines(recvlines().count(2).into()).unwrap();
+    assert_eq!(lines, vec![b"a\nb\r\n".to_vec(), b"c\r\n".to_vec()]);
+}
+
+#[cfg(unix)]
+#[test]
+fn act_all_test_unix() {
+    use super::arg::*;
+
+    let mut p = Process::try_new(ProcessArg::default().argv(&["cat"])).unwrap();
+    let res = p
+        .act_all(vec![
+            sendline().content("a").into(),
+            recvline().into(),
+            sendline().content("b").into(),
+            recvline().into(),
+        ])
+        .unwrap();
+    assert_eq!(res, vec![None, Some(b"a\n".to_vec()), None, Some(b"b\n".to_vec())]);
+
+    // a plain recv gets its answer before the next send
+    let res = p
+        .act_all(vec![
+            sendline().content("c").into(),
+            recv().timeout(Some(Duration::from_secs(1))).into(),
+            sendline().content("d").into(),
+            recvline().into(),
+        ])
+        .unwrap();
+    assert_eq!(res, vec![None, Some(b"c\n".to_vec()), None, Some(b"d\n".to_vec())]);
+}
+
+#[cfg(unix)]
+#[test]
+fn expect_test_unix() {
+    use super::arg::*;
+
+    let mut p = Process::try_new(ProcessArg::default().argv(&["cat"])).unwrap();
+    p.sendline(sendline().content("1. Success").into()).unwrap();
+    let patterns = vec![Pattern::literal("Error"), Pattern::regex(r"Succ\w+"), Pattern::literal(".")];
+    let arg = expect().patterns(patterns).timeout(Some(Duration::from_secs(1)));
+    assert_eq!(p.expect(arg.into()).unwrap(), (2, b"1.".to_vec()));
+    let arg = expect().patterns(vec![Pattern::literal("Error"), Pattern::regex(r"Succ\w+")]);
+    assert_eq!(p.expect(arg.into()).unwrap(), (1, b" Success".to_vec()));
+}
+
+#[cfg(unix)]
+#[test]
+fn pending_test_unix() {
+    use super::arg::*;
+
+    let mut p = Process::try_new(ProcessArg::default().argv(&["cat"])).unwrap();
+    p.send(send().content("line\nName").into()).unwrap();
+    let arg = recvlines().count(2).timeout(Some(Duration::from_millis(100)));
+    let e = p.recvlines(arg.into()).unwrap_err();
+    assert_eq!(e.kind, ErrorKind::Timeout);
+    assert_eq!(e.pending.unwrap(), b"line\nName");
+    match e.action {
+        Some(Action::Recvlines { count: 2, .. }) => {}
+        action => panic!("unexpected action {:?}", action),
+    }
+    // nothing got lost on the way
+    assert_eq!(p.buffer().data(), b"line\nName");
+}
@@ -0,0 +1,226 @@
+use super::error::Error;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, TcpStream};
+
+/// proxy a remote connection is tunnelled through
+#[derive(Debug, Clone)]
+pub enum Proxy {
+    /// SOCKS5 server, optionally with username and password
+    Socks5 {
+        addr: String,
+        auth: Option<(String, String)>,
+    },
+    /// HTTP proxy supporting the CONNECT method
+    Http { addr: String },
+}
+
+fn proxy_error(msg: String) -> Error {
+    io::Error::other(msg).into()
+}
+
+/// split `host:port`, IPv6 hosts may be enclosed in brackets
+fn split_host_port(addr: &str) -> Result<(&str, u16), Error> {
+    let colon = match addr.rfind(':') {
+        Some(colon) => colon,
+        None => return Err(proxy_error(format!("no port in {}", addr))),
+    };
+    let port = addr[colon + 1..]
+        .parse::<u16>()
+        .map_err(|_| proxy_error(format!("invalid port in {}", addr)))?;
+    let host = addr[..colon].trim_start_matches('[').trim_end_matches(']');
+    Ok((host, port))
+}
+
+impl Proxy {
+    /// address of the proxy itself
+    pub fn addr(&self) -> &str {
+        match self {
+            Proxy::Socks5 { addr, .. } => addr,
+            Proxy::Http { addr } => addr,
+        }
+    }
+
+    /// ask the proxy connected to by `stream` to connect further to `target`
+    pub(crate) fn tunnel(&self, stream: &mut TcpStream, target: &str) -> Result<(), Error> {
+        let (host, port) = split_host_port(target)?;
+        match self {
+            Proxy::Socks5 { auth, .. } => socks5_connect(stream, auth, host, port),
+            Proxy::Http { .. } => http_connect(stream, host, port),
+        }
+    }
+}
+
+/// SOCKS5 handshake of RFC 1928 and RFC 1929
+fn socks5_connect(
+    stream: &mut TcpStream,
+    auth: &Option<(String, String)>,
+    host: &str,
+    port: u16,
+) -> Result<(), Error> {
+    match auth {
+        Some(_) => stream.write_all(&[5, 2, 0, 2])?,
+        None => stream.write_all(&[5, 1, 0])?,
+    }
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply)?;
+    match (reply[1], auth) {
+        (0, _) => {}
+        (2, Some((user, password))) => {
+            if user.len() > 0xff || password.len() > 0xff {
+                return Err(proxy_error("SOCKS5 username or password too long".to_string()));
+            }
+            let mut req = vec![1, user.len() as u8];
+            req.extend_from_slice(user.as_bytes());
+            req.push(password.len() as u8);
+            req.extend_from_slice(password.as_bytes());
+            stream.write_all(&req)?;
+            stream.read_exact(&mut reply)?;
+            if reply[1] != 0 {
+                return Err(proxy_error("SOCKS5 authentication failed".to_string()));
+            }
+        }
+        _ => return Err(proxy_error("no acceptable SOCKS5 authentication method".to_string())),
+    }
+
+    let mut req = vec![5, 1, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            req.push(1);
+            req.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            req.push(4);
+            req.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            if host.len() > 0xff {
+                return Err(proxy_error(format!("host name {} too long for SOCKS5", host)));
+            }
+            req.push(3);
+            req.push(host.len() as u8);
+            req.extend_from_slice(host.as_bytes());
+        }
+    }
+    req.push((port >> 8) as u8);
+    req.push(port as u8);
+    stream.write_all(&req)?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[1] != 0 {
+        return Err(proxy_error(format!("SOCKS5 connect failed with code {}", reply[1])));
+    }
+    // the bound address is of no use, but has to be read past
+    let addr_len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+        atyp => return Err(proxy_error(format!("unknown SOCKS5 address type {}", atyp))),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound)?;
+    Ok(())
+}
+
+fn http_connect(stream: &mut TcpStream, host: &str, port: u16) -> Result<(), Error> {
+    let target = if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    };
+    write!(stream, "CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target)?;
+
+    // byte by byte, so nothing after the headers is taken from the tunnel
+    let mut head = vec![];
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() > 0x2000 {
+            return Err(proxy_error("HTTP proxy response too long".to_string()));
+        }
+        stream.read_exact(&mut byte)?;
+        head.push(byte[0]);
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let status = head.lines().next().unwrap_or("");
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(proxy_error(format!("HTTP proxy refused: {}", status))),
+    }
+}
+
+#[test]
+fn test_socks5() {
+    use super::arg::*;
+    use super::remote::{Remote, RemoteArg};
+    use super::tube::TubeRecv;
+    use std::net::TcpListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    // the stand-in checks the handshake, then plays the target itself
+    let proxy = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut greeting = [0u8; 4];
+        stream.read_exact(&mut greeting).unwrap();
+        assert_eq!(greeting, [5, 2, 0, 2]);
+        stream.write_all(&[5, 2]).unwrap();
+        let mut auth = [0u8; 11];
+        stream.read_exact(&mut auth).unwrap();
+        assert_eq!(&auth, b"\x01\x04user\x04pass");
+        stream.write_all(&[1, 0]).unwrap();
+        let mut req = [0u8; 20];
+        stream.read_exact(&mut req).unwrap();
+        assert_eq!(&req, b"\x05\x01\x00\x03\x0dexample.local\x01\xbb");
+        stream.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80]).unwrap();
+        stream.write_all(b"hello\n").unwrap();
+    });
+
+    let arg = RemoteArg::host("example.local:443").proxy(Proxy::Socks5 {
+        addr: addr.to_string(),
+        auth: Some(("user".to_string(), "pass".to_string())),
+    });
+    let mut p = Remote::try_new(arg).unwrap();
+    assert_eq!(&p.recvline(recvline().into()).unwrap(), b"hello\n");
+    proxy.join().unwrap();
+}
+
+#[test]
+fn test_http_connect() {
+    use super::arg::*;
+    use super::remote::{Remote, RemoteArg};
+    use super::tube::TubeRecv;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let proxy = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut stream = BufReader::new(stream);
+        let mut line = String::new();
+        stream.read_line(&mut line).unwrap();
+        assert_eq!(line, "CONNECT [::1]:1337 HTTP/1.1\r\n");
+        while line != "\r\n" {
+            line.clear();
+            stream.read_line(&mut line).unwrap();
+        }
+        stream
+            .get_mut()
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\nhello\n")
+            .unwrap();
+    });
+
+    let arg = RemoteArg::new("[::1]:1337").proxy(Proxy::Http {
+        addr: addr.to_string(),
+    });
+    let mut p = Remote::try_new(arg).unwrap();
+    assert_eq!(&p.recvline(recvline().into()).unwrap(), b"hello\n");
+    proxy.join().unwrap();
+}
@@ -0,0 +1,209 @@
+use super::arg::{Action, Timeout};
+use super::buffer::Buffer;
+use super::error::{Error, ErrorKind};
+use super::remote::{Remote, RemoteArg};
+use super::tube::{Newline, Tube, TubeExt, TubeInternal, TubeRecv, TubeSend};
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+use std::thread;
+use std::time::Duration;
+
+pub struct ReconnectArg {
+    remote: RemoteArg,
+    prelude: Vec<Action<'static>>,
+    retries: usize,
+    backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl ReconnectArg {
+    pub fn new(remote: RemoteArg) -> Self {
+        Self {
+            remote: remote,
+            prelude: vec![],
+            retries: 3,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+
+    /// actions replayed on every new connection, like login or proof of work
+    pub fn prelude(mut self, new_prelude: Vec<Action<'static>>) -> Self {
+        self.prelude = new_prelude;
+        self
+    }
+
+    /// how many times to reconnect before giving up
+    pub fn retries(mut self, new_retries: usize) -> Self {
+        self.retries = new_retries;
+        self
+    }
+
+    /// wait before the first retry, it doubles for each following one
+    pub fn backoff(mut self, new_backoff: Duration) -> Self {
+        self.backoff = new_backoff;
+        self
+    }
+
+    /// upper bound of the doubling wait
+    pub fn max_backoff(mut self, new_max_backoff: Duration) -> Self {
+        self.max_backoff = new_max_backoff;
+        self
+    }
+}
+
+/// whether the connection is gone, so reconnecting may help
+fn is_dropped(e: &Error) -> bool {
+    e.kind == ErrorKind::Eof || e.kind == ErrorKind::ConnectionReset
+}
+
+/// remote connection which reconnects when it is dropped
+///
+/// A new connection replays the prelude first, then the action that failed
+/// is retried on it. Data received but not consumed on the old connection is lost.
+pub struct Reconnect {
+    arg: ReconnectArg,
+    remote: Remote,
+}
+
+impl Reconnect {
+    pub fn try_new(arg: ReconnectArg) -> Result<Self, Error> {
+        let remote = connect(&arg)?;
+        Ok(Self {
+            arg: arg,
+            remote: remote,
+        })
+    }
+
+    /// drop the current connection and set up a new one
+    pub fn reconnect(&mut self) -> Result<(), Error> {
+        self.remote = connect(&self.arg)?;
+        Ok(())
+    }
+
+    /// run `f`, reconnecting and running it again while the connection drops
+    fn retry<T, F: FnMut(&mut Remote) -> Result<T, Error>>(&mut self, mut f: F) -> Result<T, Error> {
+        let mut retries = 0;
+        loop {
+            match f(&mut self.remote) {
+                Err(ref e) if is_dropped(e) && retries < self.arg.retries => {
+                    retries += 1;
+                    self.reconnect()?;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+/// connect and replay the prelude, with retries and backoff in between
+fn connect(arg: &ReconnectArg) -> Result<Remote, Error> {
+    let mut backoff = arg.backoff;
+    let mut retries = 0;
+    loop {
+        match connect_once(arg) {
+            Err(ref e) if retries < arg.retries && is_retryable(e) => {
+                retries += 1;
+                thread::sleep(backoff);
+                backoff = std::cmp::min(backoff * 2, arg.max_backoff);
+            }
+            res => return res,
+        }
+    }
+}
+
+fn connect_once(arg: &ReconnectArg) -> Result<Remote, Error> {
+    let mut remote = Remote::try_new(arg.remote.clone())?;
+    for action in arg.prelude.iter() {
+        remote.act(action.clone())?;
+    }
+    Ok(remote)
+}
+
+/// connecting failed or the connection dropped during the prelude, anything
+/// else, like a failed proxy handshake, won't get better by trying again
+fn is_retryable(e: &Error) -> bool {
+    match e.kind {
+        ErrorKind::ConnectionRefused | ErrorKind::Timeout => true,
+        _ => is_dropped(e),
+    }
+}
+
+impl Newline for Reconnect {
+    fn newline(&self) -> &[u8] {
+        self.remote.newline()
+    }
+}
+
+impl TubeRecv for Reconnect {
+    fn mut_buffer(&mut self) -> &mut Buffer {
+        self.remote.mut_buffer()
+    }
+
+    fn buffer(&self) -> &Buffer {
+        self.remote.buffer()
+    }
+
+    fn recv_once(&mut self, size: usize, timeout: Timeout) -> Result<Vec<u8>, Error> {
+        self.retry(|remote| remote.recv_once(size, timeout))
+    }
+
+    #[cfg(unix)]
+    fn read_fds(&self) -> Option<Vec<RawFd>> {
+        self.remote.read_fds()
+    }
+
+    fn recv(&mut self, action: Action) -> Result<Vec<u8>, Error> {
+        self.retry(|remote| remote.recv(action.clone()))
+    }
+}
+
+impl TubeSend for Reconnect {
+    fn send(&mut self, action: Action) -> Result<(), Error> {
+        self.retry(|remote| remote.send(action.clone()))
+    }
+}
+
+impl TubeInternal for Reconnect {
+    fn shutdown(&mut self, action: Action) -> Result<(), Error> {
+        self.remote.shutdown(action)
+    }
+}
+
+impl Tube for Reconnect {}
+
+#[test]
+fn test_reconnect() {
+    use super::arg::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let mut logins = vec![];
+        for i in 0..2 {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut login = String::new();
+            stream.read_line(&mut login).unwrap();
+            logins.push(login);
+            // the first connection drops right after the login
+            if i == 1 {
+                stream.get_mut().write_all(b"welcome\n").unwrap();
+            }
+        }
+        logins
+    });
+
+    let arg = ReconnectArg::new(RemoteArg::new(addr))
+        .prelude(vec![sendline().content(b"login").into()])
+        .backoff(Duration::from_millis(10));
+    let mut p = Reconnect::try_new(arg).unwrap();
+    assert_eq!(&p.recvline(recvline().into()).unwrap(), b"welcome\n");
+    assert_eq!(server.join().unwrap(), vec!["login\n", "login\n"]);
+
+    assert!(is_retryable(&Error::from_kind(ErrorKind::ConnectionRefused)));
+    assert!(is_retryable(&Error::eof()));
+    assert!(!is_retryable(&std::io::Error::other("handshake failed").into()));
+}
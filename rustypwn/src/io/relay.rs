@@ -0,0 +1,195 @@
+use super::arg::Action;
+use super::error::{Error, ErrorKind};
+#[cfg(unix)]
+use super::poll;
+use super::tube::Tube;
+use std::time::Duration;
+
+/// which way the relayed data goes
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Direction {
+    /// from the left tube to the right tube
+    LeftToRight,
+    /// from the right tube to the left tube
+    RightToLeft,
+}
+
+/// callback to inspect or modify relayed data before it is sent
+pub type RelayHook<'a> = Box<dyn FnMut(Direction, Vec<u8>) -> Vec<u8> + 'a>;
+
+pub struct RelayArg<'a> {
+    interval: Duration,
+    size: usize,
+    hook: Option<RelayHook<'a>>,
+}
+
+impl<'a> Default for RelayArg<'a> {
+    fn default() -> Self {
+        RelayArg {
+            interval: Duration::from_millis(10),
+            size: 0x1000,
+            hook: None,
+        }
+    }
+}
+
+impl<'a> RelayArg<'a> {
+    /// how long to wait on one side before checking the other one
+    ///
+    /// Only used when a side has no descriptors to wait on, otherwise both
+    /// sides are waited on at once. Every wait wakes the relay up, so while
+    /// idle it costs a wake up per interval, and each direction may lag by as
+    /// much.
+    pub fn interval(mut self, new_interval: Duration) -> Self {
+        self.interval = new_interval;
+        self
+    }
+
+    /// max size of a single chunk
+    pub fn size(mut self, new_size: usize) -> Self {
+        self.size = new_size;
+        self
+    }
+
+    /// the returned data is what actually gets sent
+    pub fn hook<F: FnMut(Direction, Vec<u8>) -> Vec<u8> + 'a>(mut self, hook: F) -> Self {
+        self.hook = Some(Box::new(hook));
+        self
+    }
+}
+
+/// move one chunk from `from` to `to`, returns false once one side is closed
+fn pump<F: Tube, T: Tube>(
+    from: &mut F,
+    to: &mut T,
+    direction: Direction,
+    arg: &mut RelayArg,
+) -> Result<bool, Error> {
+    let data = match from.mut_buffer().get(arg.size, false) {
+        Some(data) => data,
+        None => match from.recv_once(arg.size, Some(arg.interval)) {
+            Ok(data) => data,
+            Err(ref e) if e.kind == ErrorKind::Timeout => return Ok(true),
+            Err(ref e) if e.is_closed() => return Ok(false),
+            Err(e) => return Err(e),
+        },
+    };
+
+    if data.is_empty() {
+        return Ok(true);
+    }
+
+    let data = match arg.hook {
+        Some(ref mut hook) => hook(direction, data),
+        None => data,
+    };
+
+    match to.send(Action::Send {
+        timeout: None,
+        content: data.into(),
+    }) {
+        Ok(()) => Ok(true),
+        Err(ref e) if e.is_closed() => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// pump data between two tubes in both directions until either side closes
+///
+/// # Example
+///
+/// ```ignore
+/// let mut target = Process::try_new(ProcessArg::default().argv(&["./vuln"]))?;
+/// let mut server = Remote::try_new(RemoteArg::new("example.com:1337"))?;
+/// relay(&mut target, &mut server, RelayArg::default().hook(|direction, data| {
+///     println!("{:?}: {:?}", direction, data);
+///     data
+/// }))?;
+/// ```
+pub fn relay<L: Tube, R: Tube>(left: &mut L, right: &mut R, arg: RelayArg) -> Result<(), Error> {
+    let mut arg = arg;
+    loop {
+        let (left_ready, right_ready) = ready(left, right)?;
+        if left_ready && !pump(left, right, Direction::LeftToRight, &mut arg)? {
+            return Ok(());
+        }
+
+        if right_ready && !pump(right, left, Direction::RightToLeft, &mut arg)? {
+            return Ok(());
+        }
+    }
+}
+
+/// which sides to pump, after waiting on the descriptors of both at once
+/// when both have them, otherwise both take turns waiting for `interval`
+fn ready<L: Tube, R: Tube>(left: &L, right: &R) -> Result<(bool, bool), Error> {
+    // data already buffered by earlier actions goes first
+    let buffered = (!left.buffer().is_empty(), !right.buffer().is_empty());
+    if buffered.0 || buffered.1 {
+        return Ok(buffered);
+    }
+
+    #[cfg(unix)]
+    {
+        if let (Some(left_fds), Some(right_fds)) = (left.read_fds(), right.read_fds()) {
+            // a closed side tells so on the next read
+            if left_fds.is_empty() || right_fds.is_empty() {
+                return Ok((left_fds.is_empty(), right_fds.is_empty()));
+            }
+            let mut fds = left_fds
+                .iter()
+                .chain(right_fds.iter())
+                .map(|&fd| poll::pollfd(fd, libc::POLLIN))
+                .collect::<Vec<_>>();
+            poll::poll(&mut fds, None)?;
+            let (left_fds, right_fds) = fds.split_at(left_fds.len());
+            let readable = |fds: &[libc::pollfd]| fds.iter().any(|fd| fd.revents != 0);
+            return Ok((readable(left_fds), readable(right_fds)));
+        }
+    }
+
+    Ok((true, true))
+}
+
+#[test]
+fn test_relay() {
+    use super::remote::{Remote, RemoteArg};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    let client_side = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client_addr = client_side.local_addr().unwrap();
+    let client = thread::spawn(move || {
+        let (mut stream, _) = client_side.accept().unwrap();
+        stream.write_all(b"ping").unwrap();
+        let mut res = [0u8; 4];
+        stream.read_exact(&mut res).unwrap();
+        res
+    });
+
+    let server_side = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server_addr = server_side.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = server_side.accept().unwrap();
+        let mut req = [0u8; 4];
+        stream.read_exact(&mut req).unwrap();
+        stream.write_all(b"pong").unwrap();
+        req
+    });
+
+    let mut left = Remote::try_new(RemoteArg::new(client_addr)).unwrap();
+    let mut right = Remote::try_new(RemoteArg::new(server_addr)).unwrap();
+    relay(
+        &mut left,
+        &mut right,
+        RelayArg::default().hook(|direction, data| match direction {
+            Direction::LeftToRight => data.to_ascii_uppercase(),
+            Direction::RightToLeft => data,
+        }),
+    )
+    .unwrap();
+
+    assert_eq!(&server.join().unwrap(), b"PING");
+    assert_eq!(&client.join().unwrap(), b"pong");
+}
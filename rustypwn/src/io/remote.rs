@@ -2,55 +2,249 @@ use rustypwn_derive::action;
 
 use std::io::{Read, Write};
 use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
-use std::time::{SystemTime, Duration};
-use super::error::Error;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use super::error::{stream_error, Error, ErrorKind};
 use super::buffer::Buffer;
-use super::tube::{TubeInternal, Tube};
+use super::tube::{Newline, TubeInternal, TubeRecv, TubeSend, Tube};
 use super::arg::{Action, Timeout};
+use super::proxy::Proxy;
+use super::telnet::{self, Telnet};
 
-#[derive(Debug)]
+/// what a remote connects to
+#[derive(Debug, Clone)]
+enum Target {
+    /// resolved by `RemoteArg::new`, or why resolving failed
+    Addrs(Result<Vec<SocketAddr>, String>),
+    /// `host:port` resolved when connecting, or by the proxy
+    Host(String),
+}
+
+#[derive(Debug, Clone)]
 pub struct RemoteArg {
-    addr: SocketAddr,
+    target: Target,
     timeout: Timeout,
+    bind: Option<SocketAddr>,
+    proxy: Option<Proxy>,
+    newline: Vec<u8>,
+    telnet: bool,
 }
 
 impl RemoteArg {
+    /// `addr_repr` is resolved right away, `try_new` reports when that failed
     pub fn new<T: ToSocketAddrs>(addr_repr: T) -> Self {
+        let addrs = addr_repr
+            .to_socket_addrs()
+            .map(|addrs| addrs.collect())
+            .map_err(|e| e.to_string());
+        Self::with_target(Target::Addrs(addrs))
+    }
+
+    /// `addr_repr` is formatted as `host:port` and resolved when connecting,
+    /// or by the proxy when there is one
+    pub fn host<T: ToString>(addr_repr: T) -> Self {
+        Self::with_target(Target::Host(addr_repr.to_string()))
+    }
+
+    fn with_target(target: Target) -> Self {
         Self {
-            addr: addr_repr.to_socket_addrs().unwrap().next().unwrap(),
+            target: target,
             timeout: None,
+            bind: None,
+            proxy: None,
+            newline: b"\n".to_vec(),
+            telnet: false,
         }
     }
 
+    /// timeout of connecting to each resolved address
     pub fn timeout(mut self, new_timeout: Timeout) -> Self {
         self.timeout = new_timeout;
         self
     }
+
+    /// local address to connect from, port 0 lets the system pick one
+    pub fn bind(mut self, local_addr: SocketAddr) -> Self {
+        self.bind = Some(local_addr);
+        self
+    }
+
+    /// tunnel the connection through a proxy, the target is then resolved by the proxy
+    pub fn proxy(mut self, new_proxy: Proxy) -> Self {
+        self.proxy = Some(new_proxy);
+        self
+    }
+
+    /// line terminator of `sendline` and `recvline`, `\n` by default
+    pub fn newline(mut self, new_newline: &[u8]) -> Self {
+        self.newline = new_newline.to_vec();
+        self
+    }
+
+    /// talk to a service behind telnetd: option negotiation is refused and kept
+    /// out of what is received, `0xff` in what is sent is escaped
+    pub fn telnet(mut self, new_telnet: bool) -> Self {
+        self.telnet = new_telnet;
+        self
+    }
+
+    /// every address the target resolves to, IPv4 and IPv6
+    pub fn resolve(&self) -> Result<Vec<SocketAddr>, Error> {
+        match self.target {
+            Target::Addrs(Ok(ref addrs)) if !addrs.is_empty() => Ok(addrs.clone()),
+            Target::Addrs(Ok(_)) => Err(resolve_error("no address found".to_string())),
+            Target::Addrs(Err(ref e)) => Err(resolve_error(e.clone())),
+            Target::Host(ref addr_repr) => resolve(addr_repr),
+        }
+    }
+
+    /// the target as the proxy is asked for it
+    fn target_repr(&self) -> Result<String, Error> {
+        match self.target {
+            Target::Host(ref addr_repr) => Ok(addr_repr.clone()),
+            Target::Addrs(_) => Ok(self.resolve()?[0].to_string()),
+        }
+    }
+
+    /// connect to the first of `addrs` accepting the connection
+    fn connect_any(&self, addrs: Vec<SocketAddr>) -> Result<TcpStream, Error> {
+        let mut last_error = None;
+        for addr in addrs {
+            // a local address only fits targets of the same family
+            if let Some(ref local_addr) = self.bind {
+                if local_addr.is_ipv4() != addr.is_ipv4() {
+                    continue;
+                }
+            }
+
+            match self.connect(&addr) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        match last_error {
+            Some(e) => Err(stream_error(e)),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "no address of the target matches the bound address",
+            )
+            .into()),
+        }
+    }
+
+    fn connect(&self, addr: &SocketAddr) -> std::io::Result<TcpStream> {
+        let domain = if addr.is_ipv4() {
+            Domain::ipv4()
+        } else {
+            Domain::ipv6()
+        };
+        let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+        if let Some(ref local_addr) = self.bind {
+            socket.bind(&SockAddr::from(*local_addr))?;
+        }
+
+        match self.timeout {
+            Some(timeout) => socket.connect_timeout(&SockAddr::from(*addr), timeout)?,
+            None => socket.connect(&SockAddr::from(*addr))?,
+        }
+        Ok(socket.into_tcp_stream())
+    }
 }
 
-pub struct Remote {
+fn resolve_error(msg: String) -> Error {
+    let e = std::io::Error::new(std::io::ErrorKind::NotFound, msg);
+    Error::with_source(ErrorKind::Resolve, Box::new(e))
+}
+
+fn resolve(addr_repr: &str) -> Result<Vec<SocketAddr>, Error> {
+    let addrs = addr_repr
+        .to_socket_addrs()
+        .map_err(|e| Error::with_source(ErrorKind::Resolve, Box::new(e)))?
+        .collect::<Vec<_>>();
+    if addrs.is_empty() {
+        return Err(resolve_error(format!("no address found for {}", addr_repr)));
+    }
+    Ok(addrs)
+}
+
+/// the writing socket, still usable after a thread panicked holding it
+fn lock(stream: &Mutex<TcpStream>) -> MutexGuard<'_, TcpStream> {
+    stream.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// receiving half of a remote connection
+pub struct RemoteReader {
     buf: Buffer,
     stream: TcpStream,
+    newline: Vec<u8>,
+    telnet: Option<Telnet>,
+    /// the socket of the writer, telnet answers go out under its lock so
+    /// they never land in the middle of a send from another thread
+    writer: Arc<Mutex<TcpStream>>,
+}
+
+/// sending half of a remote connection
+pub struct RemoteWriter {
+    stream: Arc<Mutex<TcpStream>>,
+    newline: Vec<u8>,
+    telnet: bool,
+}
+
+pub struct Remote {
+    reader: RemoteReader,
+    writer: RemoteWriter,
 }
 
 impl Remote {
     pub fn try_new(arg: RemoteArg) -> Result<Self, Error> {
-        let stream = TcpStream::connect(arg.addr)?;
+        let stream = match arg.proxy {
+            Some(ref proxy) => {
+                let mut stream = arg.connect_any(resolve(proxy.addr())?)?;
+                stream.set_read_timeout(arg.timeout)?;
+                stream.set_write_timeout(arg.timeout)?;
+                proxy.tunnel(&mut stream, &arg.target_repr()?)?;
+                stream.set_read_timeout(None)?;
+                stream.set_write_timeout(None)?;
+                stream
+            }
+            None => arg.connect_any(arg.resolve()?)?,
+        };
         stream.set_nonblocking(false)?;
+        let writer = Arc::new(Mutex::new(stream.try_clone()?));
         Ok(Self {
-            buf: Buffer::default(),
-            stream: stream
+            reader: RemoteReader {
+                buf: Buffer::default(),
+                stream: stream,
+                newline: arg.newline.clone(),
+                telnet: if arg.telnet { Some(Telnet::default()) } else { None },
+                writer: writer.clone(),
+            },
+            writer: RemoteWriter {
+                stream: writer,
+                newline: arg.newline,
+                telnet: arg.telnet,
+            },
         })
     }
+
+    /// split into halves which can be used from different threads,
+    /// the connection is closed once both halves are dropped
+    pub fn split(self) -> (RemoteReader, RemoteWriter) {
+        (self.reader, self.writer)
+    }
 }
 
-impl Drop for Remote {
-    fn drop(&mut self) {
-        std::mem::drop(&mut self.stream)
+impl Newline for RemoteReader {
+    fn newline(&self) -> &[u8] {
+        &self.newline
     }
 }
 
-impl TubeInternal for Remote {
+impl TubeRecv for RemoteReader {
     fn mut_buffer(&mut self) -> &mut Buffer {
         &mut self.buf
     }
@@ -59,56 +253,91 @@ impl TubeInternal for Remote {
         &self.buf
     }
 
+    fn recv_once(&mut self, size: usize, timeout: Option<Duration>) -> Result<Vec<u8>, Error> {
+        let mut content = vec![0u8; size];
+
+        self.stream.set_read_timeout(timeout)?;
+        let n = self.stream.read(&mut content).map_err(stream_error)?;
+        if n == 0 && size != 0 {
+            return Err(Error::eof());
+        }
+        content.truncate(n);
+
+        // negotiation leaves nothing behind, which just means reading on
+        if let Some(ref mut telnet) = self.telnet {
+            let (data, answers) = telnet.filter(&content);
+            if !answers.is_empty() {
+                let mut writer = lock(&self.writer);
+                writer.set_write_timeout(timeout)?;
+                writer.write_all(&answers).map_err(stream_error)?;
+            }
+            content = data;
+        }
+        Ok(content)
+    }
+
+    #[cfg(unix)]
+    fn read_fds(&self) -> Option<Vec<RawFd>> {
+        Some(vec![self.stream.as_raw_fd()])
+    }
+}
+
+impl Newline for RemoteWriter {
+    fn newline(&self) -> &[u8] {
+        &self.newline
+    }
+}
+
+impl TubeSend for RemoteWriter {
     #[action(timeout, content)]
     fn send(&mut self, action: Action) -> Result<(), Error> {
-        self.stream.set_write_timeout(timeout)?;
-        self.stream.write(&content)
-            .map_err(|e| if e.kind() == std::io::ErrorKind::TimedOut { Error::timeout() } else { e.into() })?;
+        let mut stream = lock(&self.stream);
+        stream.set_write_timeout(timeout)?;
+        if self.telnet {
+            stream.write_all(&telnet::escape(&content)).map_err(stream_error)?;
+        } else {
+            stream.write_all(&content).map_err(stream_error)?;
+        }
         Ok(())
     }
+}
 
-    fn recv_once(&mut self, size: usize, timeout: Option<Duration>) -> Result<Vec<u8>, Error> {
-        let mut content = Vec::with_capacity(size);
-        content.resize(size, 0u8);
+impl Newline for Remote {
+    fn newline(&self) -> &[u8] {
+        self.reader.newline()
+    }
+}
 
-        self.stream.set_read_timeout(timeout)?;
-        let n = self.stream.read(&mut content)
-            .map_err(|e| if e.kind() == std::io::ErrorKind::TimedOut { Error::timeout() } else { e.into() })?;
-        Ok(content[0..n].to_vec())
+impl TubeRecv for Remote {
+    fn mut_buffer(&mut self) -> &mut Buffer {
+        self.reader.mut_buffer()
     }
 
-    #[action(timeout, size, must)]
-    fn recv(&mut self, action: Action) -> Result<Vec<u8>, Error> {
-        let now = SystemTime::now();
-        let mut content = Vec::with_capacity(size);
-        content.resize(size, 0u8);
+    fn buffer(&self) -> &Buffer {
+        self.reader.buffer()
+    }
 
-        self.stream.set_read_timeout(timeout)?;
+    fn recv_once(&mut self, size: usize, timeout: Option<Duration>) -> Result<Vec<u8>, Error> {
+        self.reader.recv_once(size, timeout)
+    }
 
-        loop {
-            let res = self.mut_buffer().get(size, must);
-            if let Some(res) = res {
-                return Ok(res);
-            }
+    #[cfg(unix)]
+    fn read_fds(&self) -> Option<Vec<RawFd>> {
+        self.reader.read_fds()
+    }
 
-            let n = self.stream.read(&mut content)
-                .map_err(|e| if e.kind() == std::io::ErrorKind::TimedOut { Error::timeout() } else { e.into() } )?;
-            let mut put = content[0..n].to_vec();
-            self.mut_buffer().append(&mut put);
-
-            if let Some(timeout) = timeout {
-                match now.elapsed() {
-                    Ok(elapsed) => {
-                        if elapsed >= timeout {
-                            return Err(Error::timeout());
-                        }
-                    },
-                    _ => panic!("get time error, internal bug"),
-                }
-            }
-        }
+    fn recv(&mut self, action: Action) -> Result<Vec<u8>, Error> {
+        self.reader.recv(action)
     }
+}
 
+impl TubeSend for Remote {
+    fn send(&mut self, action: Action) -> Result<(), Error> {
+        self.writer.send(action)
+    }
+}
+
+impl TubeInternal for Remote {
     #[action(stdin, stdout)]
     fn shutdown(&mut self, action: Action) -> Result<(), Error> {
         let shutdown = {
@@ -121,7 +350,7 @@ impl TubeInternal for Remote {
             }
         };
 
-        Ok(self.stream.shutdown(shutdown)?)
+        Ok(self.reader.stream.shutdown(shutdown)?)
     }
 }
 
@@ -132,6 +361,115 @@ fn test_remote() {
     use super::arg::*;
 
     let mut p = Remote::try_new(RemoteArg::new("localhost:11222")).unwrap();
-    p.sendline(sendline().content(b"hello".to_vec()).into()).unwrap();
+    p.sendline(sendline().content(b"hello").into()).unwrap();
     assert_eq!(&p.recvline(recvline().into()).unwrap(), b"yes\n");
 }
+
+#[test]
+fn test_split() {
+    use super::arg::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut req = [0u8; 6];
+        stream.read_exact(&mut req).unwrap();
+        stream.write_all(b"world\n").unwrap();
+        req
+    });
+
+    let p = Remote::try_new(RemoteArg::new(addr)).unwrap();
+    let (mut reader, mut writer) = p.split();
+    let recving = thread::spawn(move || reader.recvline(recvline().into()).unwrap());
+    writer.sendline(sendline().content(b"hello").into()).unwrap();
+
+    assert_eq!(&recving.join().unwrap(), b"world\n");
+    assert_eq!(&server.join().unwrap(), b"hello\n");
+}
+
+#[test]
+fn test_act_all() {
+    use super::arg::{recvline, sendline};
+    use std::io::copy;
+    use std::net::TcpListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        copy(&mut stream.try_clone().unwrap(), &mut &stream).unwrap();
+    });
+
+    // far more than the socket buffers hold, the answers are read while sending
+    let line = "A".repeat(1023);
+    let mut actions = vec![];
+    for _ in 0..20000 {
+        actions.push(sendline().content(line.as_bytes()).into());
+        actions.push(recvline().into());
+    }
+    let mut p = Remote::try_new(RemoteArg::new(addr)).unwrap();
+    let res = p.act_all(actions).unwrap();
+    assert_eq!(res.len(), 40000);
+    assert_eq!(res[39999].as_ref().unwrap().len(), 1024);
+    drop(p);
+    server.join().unwrap();
+}
+
+#[test]
+fn test_telnet() {
+    use super::arg::ActionMethods;
+    use std::net::TcpListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        // IAC DO ECHO, IAC WILL SUPPRESS-GO-AHEAD, the second split across writes
+        stream.write_all(b"\xff\xfd\x01he\xff\xfb").unwrap();
+        stream.flush().unwrap();
+        thread::sleep(Duration::from_millis(20));
+        stream.write_all(b"\x03llo \xff\xff\n").unwrap();
+        let mut req = [0u8; 8];
+        stream.read_exact(&mut req).unwrap();
+        req
+    });
+
+    let mut p = Remote::try_new(RemoteArg::new(addr).telnet(true)).unwrap();
+    assert_eq!(p.recv_line().unwrap(), b"hello \xff\n");
+    p.send_bytes(b"a\xffb").unwrap();
+    // IAC WONT ECHO, IAC DONT SUPPRESS-GO-AHEAD, then the escaped data
+    assert_eq!(&server.join().unwrap(), b"\xff\xfc\x01\xff\xfe\x03a\xff");
+}
+
+#[test]
+fn test_connect() {
+    use std::net::TcpListener;
+    use std::thread;
+
+    let e = Remote::try_new(RemoteArg::new("no port")).err().unwrap();
+    assert_eq!(e.kind, ErrorKind::Resolve);
+    let e = Remote::try_new(RemoteArg::host("no port")).err().unwrap();
+    assert_eq!(e.kind, ErrorKind::Resolve);
+
+    // nobody listens on a port just given back
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let e = Remote::try_new(RemoteArg::new(("127.0.0.1", port))).err().unwrap();
+    assert_eq!(e.kind, ErrorKind::ConnectionRefused);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || listener.accept().unwrap().1);
+
+    // localhost may resolve to ::1 first, which nobody listens on
+    let arg = RemoteArg::host(format!("localhost:{}", port))
+        .timeout(Some(Duration::from_secs(1)))
+        .bind("127.0.0.1:0".parse().unwrap());
+    let p = Remote::try_new(arg).unwrap();
+    let local_addr = p.reader.stream.local_addr().unwrap();
+    assert_eq!(server.join().unwrap(), local_addr);
+}
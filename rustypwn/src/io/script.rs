@@ -0,0 +1,249 @@
+//! action lists written down in TOML, JSON or YAML, run against any tube
+//!
+//! A script is a table with a list of actions, each tagged by its name:
+//!
+//! ```toml
+//! [[actions]]
+//! action = "recvuntil"
+//! pattern = "canary: (?P<canary>[0-9a-f]+)\n"
+//!
+//! [[actions]]
+//! action = "sendline"
+//! content = "{canary}"
+//! timeout = 1.5
+//! ```
+//!
+//! Named groups of `recvuntil` patterns, and of the `expect` pattern that
+//! matched, capture variables. `{name}` substitutes them into the content of
+//! later sends and into later regex patterns, matching the captured bytes
+//! literally. `{{name}}` stands for `{name}` itself, matched literally in
+//! patterns too. Braces around anything but a name are kept, like those of
+//! `[0-9a-f]{16}`.
+
+use super::arg::{literal_pattern, Action, Pattern};
+use super::error::{Error, ErrorKind};
+use super::tube::{Tube, TubeExt};
+use regex::bytes::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+
+/// captured variables by name
+pub type Variables = HashMap<String, Vec<u8>>;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Script {
+    pub actions: Vec<Action<'static>>,
+}
+
+fn script_error(msg: String) -> Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg).into()
+}
+
+/// the name `text` starts with, if it starts with one
+fn identifier(text: &[u8]) -> Option<&[u8]> {
+    let len = text
+        .iter()
+        .position(|&b| !(b.is_ascii_alphanumeric() || b == b'_'))
+        .unwrap_or(text.len());
+    match text.first() {
+        Some(b) if len > 0 && !b.is_ascii_digit() => Some(&text[..len]),
+        _ => None,
+    }
+}
+
+/// replace every `{name}` in `text` by the variable, escaped by `escape`, and
+/// every `{{name}}` by `{name}` with the braces escaped the same way
+///
+/// Braces around anything but a name are left alone, so regex repetitions
+/// like `{16}` and raw bytes stay as they are.
+fn substitute<F: Fn(&[u8]) -> Vec<u8>>(text: &[u8], vars: &Variables, escape: F) -> Result<Vec<u8>, Error> {
+    let mut res = Vec::with_capacity(text.len());
+    let mut i = 0;
+    while i < text.len() {
+        let rest = &text[i..];
+        if rest.starts_with(b"{{") {
+            if let Some(name) = identifier(&rest[2..]) {
+                if rest[2 + name.len()..].starts_with(b"}}") {
+                    res.extend(escape(b"{"));
+                    res.extend_from_slice(name);
+                    res.extend(escape(b"}"));
+                    i += name.len() + 4;
+                    continue;
+                }
+            }
+        }
+        if rest.starts_with(b"{") {
+            if let Some(name) = identifier(&rest[1..]) {
+                if rest[1 + name.len()..].starts_with(b"}") {
+                    let name = String::from_utf8_lossy(name);
+                    match vars.get(name.as_ref()) {
+                        Some(value) => res.extend(escape(value)),
+                        None => return Err(script_error(format!("unknown variable {}", name))),
+                    }
+                    i += name.len() + 2;
+                    continue;
+                }
+            }
+        }
+        res.push(text[i]);
+        i += 1;
+    }
+    Ok(res)
+}
+
+impl Script {
+    pub fn new(actions: Vec<Action<'static>>) -> Self {
+        Self { actions: actions }
+    }
+
+    pub fn from_toml(script: &str) -> Result<Self, Error> {
+        toml::from_str(script).map_err(|e| Error::from_source(Box::new(e)))
+    }
+
+    pub fn from_json(script: &str) -> Result<Self, Error> {
+        serde_json::from_str(script).map_err(|e| Error::from_source(Box::new(e)))
+    }
+
+    pub fn from_yaml(script: &str) -> Result<Self, Error> {
+        serde_yaml::from_str(script).map_err(|e| Error::from_source(Box::new(e)))
+    }
+
+    /// run all actions in order, returns the variables captured
+    pub fn run<T: Tube + ?Sized>(&self, tube: &mut T) -> Result<Variables, Error> {
+        self.run_with(tube, Variables::new())
+    }
+
+    /// like `run`, with some variables known upfront
+    pub fn run_with<T: Tube + ?Sized>(&self, tube: &mut T, mut vars: Variables) -> Result<Variables, Error> {
+        for action in self.actions.iter() {
+            let action = resolve(action, &vars)?;
+            match action {
+                Action::Recvuntil { ref pattern, .. } => {
+                    let pattern = pattern.clone();
+                    let res = tube.recvuntil(action)?;
+                    capture(&pattern, &res, &mut vars)?;
+                }
+                Action::Expect { ref patterns, .. } => {
+                    let patterns = patterns.clone();
+                    let (index, res) = tube.expect(action)?;
+                    capture(&patterns[index].to_regex(), &res, &mut vars)?;
+                }
+                action => {
+                    tube.act(action)?;
+                }
+            }
+        }
+        Ok(vars)
+    }
+}
+
+fn substitute_pattern(pattern: &str, vars: &Variables) -> Result<String, Error> {
+    let pattern = substitute(pattern.as_bytes(), vars, |value| literal_pattern(value).into_bytes())?;
+    // only ASCII was put in
+    Ok(String::from_utf8_lossy(&pattern).into_owned())
+}
+
+/// `action` with variables substituted
+fn resolve(action: &Action<'static>, vars: &Variables) -> Result<Action<'static>, Error> {
+    Ok(match action {
+        Action::Send { timeout, content } => Action::Send {
+            timeout: *timeout,
+            content: substitute(content, vars, |value| value.to_vec())?.into(),
+        },
+        Action::Sendline { timeout, content } => Action::Sendline {
+            timeout: *timeout,
+            content: substitute(content, vars, |value| value.to_vec())?.into(),
+        },
+        Action::Recvuntil { timeout, pattern } => Action::Recvuntil {
+            timeout: *timeout,
+            pattern: substitute_pattern(pattern, vars)?,
+        },
+        Action::Expect { timeout, patterns } => Action::Expect {
+            timeout: *timeout,
+            patterns: patterns
+                .iter()
+                .map(|pattern| match pattern {
+                    Pattern::Regex(pattern) => substitute_pattern(pattern, vars).map(Pattern::Regex),
+                    pattern => Ok(pattern.clone()),
+                })
+                .collect::<Result<_, _>>()?,
+        },
+        action => action.clone(),
+    })
+}
+
+/// store the named groups of `pattern` matching in `data`
+fn capture(pattern: &str, data: &[u8], vars: &mut Variables) -> Result<(), Error> {
+    let re = Regex::new(pattern).map_err(|e| Error::with_source(ErrorKind::InvalidPattern, Box::new(e)))?;
+    let caps = match re.captures(data) {
+        Some(caps) => caps,
+        None => return Ok(()),
+    };
+    for name in re.capture_names().flatten() {
+        if let Some(mat) = caps.name(name) {
+            vars.insert(name.to_string(), mat.as_bytes().to_vec());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn script_test_unix() {
+    use super::process::{Process, ProcessArg};
+
+    let script = Script::from_toml(
+        r#"
+        [[actions]]
+        action = "sendline"
+        content = "canary: 1234"
+
+        [[actions]]
+        action = "recvuntil"
+        pattern = "canary: (?P<canary>\\d{4})\n"
+
+        [[actions]]
+        action = "sendline"
+        content = "{canary}+{{x}}"
+        timeout = 1.5
+
+        [[actions]]
+        action = "expect"
+        patterns = [{ literal = "Error" }, { regex = "{canary}\\+(?P<rest>.*)\n" }]
+
+        [[actions]]
+        action = "sendline"
+        content = "{{y}}"
+
+        [[actions]]
+        action = "recvuntil"
+        pattern = "{{y}}\n"
+        "#,
+    )
+    .unwrap();
+    let mut p = Process::try_new(ProcessArg::default().argv(&["cat"])).unwrap();
+    let vars = script.run(&mut p).unwrap();
+    assert_eq!(vars["canary"], b"1234");
+    assert_eq!(vars["rest"], b"{x}");
+
+    let json = serde_json::to_string(&script).unwrap();
+    let script = Script::from_json(&json).unwrap();
+    let yaml = serde_yaml::to_string(&script).unwrap();
+    let script = Script::from_yaml(&yaml).unwrap();
+    let mut p = Process::try_new(ProcessArg::default().argv(&["cat"])).unwrap();
+    assert_eq!(script.run(&mut p).unwrap()["canary"], b"1234");
+    assert!(Script::from_json(r#"{"actions": [{"action": "sendline", "content": "{nope}"}]}"#)
+        .unwrap()
+        .run(&mut p)
+        .is_err());
+
+    // braces of raw bytes aren't variables
+    let vars = Variables::new();
+    let packed = b"\x7b\x10\x40\x00{1,2}";
+    assert_eq!(substitute(packed, &vars, |value| value.to_vec()).unwrap(), packed);
+    let script = Script::from_json(r#"{"actions": [{"action": "send", "content": [123, 16, 64, 0]}]}"#).unwrap();
+    let mut p = Process::try_new(ProcessArg::default().argv(&["cat"])).unwrap();
+    script.run(&mut p).unwrap();
+    assert_eq!(p.act(super::arg::recv().size(4).must(true)).unwrap().unwrap(), b"\x7b\x10\x40\x00");
+}
@@ -0,0 +1,132 @@
+//! `std::io` traits for tubes, so standard tooling works on them directly
+
+use super::arg::Action;
+#[cfg(unix)]
+use super::process::{Process, ProcessReader, ProcessWriter};
+use super::remote::{Remote, RemoteReader, RemoteWriter};
+use super::tube::{TubeRecv, TubeSend};
+use std::io;
+
+/// size of a single read when the internal buffer runs dry
+const CHUNK_SIZE: usize = 0x1000;
+
+/// a closed tube simply reads as EOF
+fn recv_some<T: TubeRecv>(tube: &mut T, size: usize) -> io::Result<Vec<u8>> {
+    let res = tube.recv(Action::Recv {
+        timeout: None,
+        size: size,
+        must: false,
+    });
+    match res {
+        Ok(res) => Ok(res),
+        Err(ref e) if e.is_closed() => Ok(vec![]),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn read<T: TubeRecv>(tube: &mut T, buf: &mut [u8]) -> io::Result<usize> {
+    if buf.is_empty() {
+        return Ok(0);
+    }
+
+    let data = recv_some(tube, buf.len())?;
+    buf[..data.len()].copy_from_slice(&data);
+    Ok(data.len())
+}
+
+fn fill_buf<T: TubeRecv>(tube: &mut T) -> io::Result<&[u8]> {
+    if tube.buffer().data().is_empty() {
+        let mut data = recv_some(tube, CHUNK_SIZE)?;
+        tube.mut_buffer().append(&mut data);
+    }
+    Ok(tube.buffer().data())
+}
+
+fn write<T: TubeSend>(tube: &mut T, buf: &[u8]) -> io::Result<usize> {
+    tube.send(Action::Send {
+        timeout: None,
+        content: buf.into(),
+    })?;
+    Ok(buf.len())
+}
+
+macro_rules! impl_read {
+    ($($tube:ty),*) => {
+        $(
+            impl io::Read for $tube {
+                fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                    read(self, buf)
+                }
+            }
+
+            impl io::BufRead for $tube {
+                fn fill_buf(&mut self) -> io::Result<&[u8]> {
+                    fill_buf(self)
+                }
+
+                fn consume(&mut self, amt: usize) {
+                    self.mut_buffer().consume(amt)
+                }
+            }
+        )*
+    }
+}
+
+macro_rules! impl_write {
+    ($($tube:ty),*) => {
+        $(
+            impl io::Write for $tube {
+                fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                    write(self, buf)
+                }
+
+                fn flush(&mut self) -> io::Result<()> {
+                    // nothing is buffered on the sending side
+                    Ok(())
+                }
+            }
+        )*
+    }
+}
+
+impl_read!(Remote, RemoteReader);
+impl_write!(Remote, RemoteWriter);
+#[cfg(unix)]
+impl_read!(Process, ProcessReader);
+#[cfg(unix)]
+impl_write!(Process, ProcessWriter);
+
+#[test]
+fn test_std_io() {
+    use super::remote::RemoteArg;
+    use super::tube::TubeInternal;
+    use std::io::{BufRead, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(b"first\nsecond\nrest").unwrap();
+        let mut req = vec![];
+        stream.read_to_end(&mut req).unwrap();
+        req
+    });
+
+    let mut p = Remote::try_new(RemoteArg::new(addr)).unwrap();
+    write!(p, "hello {}", 1).unwrap();
+    let mut line = String::new();
+    p.read_line(&mut line).unwrap();
+    assert_eq!(line, "first\n");
+
+    p.shutdown(Action::Shutdown {
+        stdin: true,
+        stdout: false,
+    })
+    .unwrap();
+    let mut rest = String::new();
+    p.read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "second\nrest");
+    assert_eq!(&server.join().unwrap(), b"hello 1");
+}
@@ -0,0 +1,203 @@
+//! tube over the stdin and stdout of the running program, to talk to whatever
+//! it is run under, like `socat` or an `ssh` pipe
+//!
+//! Not to be confused with `std_io`, which puts `std::io` traits on tubes.
+
+use rustypwn_derive::action;
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use super::arg::{Action, Timeout};
+use super::buffer::Buffer;
+use super::error::{stream_error, Error};
+use super::poll::{self, Deadline};
+use super::tube::{Newline, Tube, TubeInternal, TubeRecv, TubeSend};
+
+/// the program's own stdin and stdout as a tube
+///
+/// Reads go straight to the descriptors, bypassing `std::io::stdin()`, so
+/// don't read stdin elsewhere while using it. Whatever `print!` buffered is
+/// flushed before each send, so the order of output is kept.
+pub struct Stdio {
+    buf: Buffer,
+    read_fd: RawFd,
+    write_fd: RawFd,
+    recv_closed: bool,
+    send_closed: bool,
+    newline: Vec<u8>,
+}
+
+impl Stdio {
+    pub fn new() -> Self {
+        Self::from_fds(libc::STDIN_FILENO, libc::STDOUT_FILENO)
+    }
+
+    fn from_fds(read_fd: RawFd, write_fd: RawFd) -> Self {
+        Self {
+            buf: Buffer::default(),
+            read_fd: read_fd,
+            write_fd: write_fd,
+            recv_closed: false,
+            send_closed: false,
+            newline: b"\n".to_vec(),
+        }
+    }
+
+    /// line terminator of `sendline` and `recvline`, `\n` by default
+    pub fn newline(mut self, new_newline: &[u8]) -> Self {
+        self.newline = new_newline.to_vec();
+        self
+    }
+
+    fn write_before(&mut self, content: &[u8], deadline: Deadline) -> Result<(), Error> {
+        let mut written = 0;
+        while written < content.len() {
+            if !poll::poll(&mut [poll::pollfd(self.write_fd, libc::POLLOUT)], deadline)? {
+                return Err(Error::timeout());
+            }
+
+            let rest = &content[written..];
+            let n = unsafe { libc::write(self.write_fd, rest.as_ptr() as *const libc::c_void, rest.len()) };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                match e.kind() {
+                    io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => {}
+                    _ => return Err(stream_error(e)),
+                }
+            } else {
+                written += n as usize;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Stdio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// point `fd` at `/dev/null`, which closes what was there without freeing the
+/// number, so nothing opened later ends up as stdin or stdout
+fn close_fd(fd: RawFd) -> Result<(), Error> {
+    let null = OpenOptions::new().read(true).write(true).open("/dev/null")?;
+    if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+impl Newline for Stdio {
+    fn newline(&self) -> &[u8] {
+        &self.newline
+    }
+}
+
+impl TubeRecv for Stdio {
+    fn mut_buffer(&mut self) -> &mut Buffer {
+        &mut self.buf
+    }
+
+    fn buffer(&self) -> &Buffer {
+        &self.buf
+    }
+
+    fn recv_once(&mut self, size: usize, timeout: Timeout) -> Result<Vec<u8>, Error> {
+        if self.recv_closed {
+            return Err(Error::eof());
+        }
+
+        let deadline = poll::deadline(timeout);
+        let mut content = vec![0u8; size];
+        loop {
+            if !poll::poll(&mut [poll::pollfd(self.read_fd, libc::POLLIN)], deadline)? {
+                return Err(Error::timeout());
+            }
+
+            let n = unsafe { libc::read(self.read_fd, content.as_mut_ptr() as *mut libc::c_void, size) };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                match e.kind() {
+                    io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => continue,
+                    _ => return Err(stream_error(e)),
+                }
+            }
+            if n == 0 && size != 0 {
+                return Err(Error::eof());
+            }
+            content.truncate(n as usize);
+            return Ok(content);
+        }
+    }
+
+    fn read_fds(&self) -> Option<Vec<RawFd>> {
+        if self.recv_closed {
+            return Some(vec![]);
+        }
+        Some(vec![self.read_fd])
+    }
+}
+
+impl TubeSend for Stdio {
+    #[action(timeout, content)]
+    fn send(&mut self, action: Action) -> Result<(), Error> {
+        if self.send_closed {
+            return Err(Error::eof());
+        }
+        io::stdout().flush()?;
+        self.write_before(&content, poll::deadline(timeout))
+    }
+}
+
+impl TubeInternal for Stdio {
+    #[action(stdin, stdout)]
+    fn shutdown(&mut self, action: Action) -> Result<(), Error> {
+        // the stdin of the other side is our stdout
+        if stdin && !self.send_closed {
+            io::stdout().flush()?;
+            close_fd(self.write_fd)?;
+            self.send_closed = true;
+        }
+        if stdout && !self.recv_closed {
+            close_fd(self.read_fd)?;
+            self.recv_closed = true;
+        }
+        Ok(())
+    }
+}
+
+impl Tube for Stdio {}
+
+#[test]
+fn test_stdio() {
+    use super::arg::ActionMethods;
+    use super::tube::TubeExt;
+    use std::io::Read;
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    // sockets standing in for stdin and stdout, they don't leak into children
+    let (mut input, stdin) = UnixStream::pair().unwrap();
+    let (mut output, stdout) = UnixStream::pair().unwrap();
+    let (stdin, stdout) = (stdin.into_raw_fd(), stdout.into_raw_fd());
+
+    let mut p = Stdio::from_fds(stdin, stdout);
+    input.write_all(b"hello\n").unwrap();
+    assert_eq!(p.recv_line().unwrap(), b"hello\n");
+    let e = p.with_timeout(Duration::from_millis(50)).recv_line().unwrap_err();
+    assert!(!e.is_closed() && e.is_incomplete());
+
+    p.sendline_bytes("world").unwrap();
+    p.close().unwrap();
+    let mut res = vec![];
+    output.read_to_end(&mut res).unwrap();
+    assert_eq!(res, b"world\n");
+    assert!(p.recv_some(8).unwrap_err().is_closed());
+    unsafe {
+        libc::close(stdin);
+        libc::close(stdout);
+    }
+}
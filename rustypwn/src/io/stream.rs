@@ -0,0 +1,287 @@
+//! tubes over any `Read + Write` stream, for transports without a tube of their own
+
+use rustypwn_derive::action;
+
+use std::io::{self, Read, Write};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+use super::arg::{Action, Timeout};
+use super::buffer::Buffer;
+use super::error::{stream_error, Error};
+#[cfg(unix)]
+use super::poll;
+use super::tube::{Newline, Tube, TubeInternal, TubeRecv, TubeSend};
+
+/// a reader and a writer acting as one stream, like the pipes of a child
+///
+/// ```ignore
+/// let stream = Duplex::new(child.stdout.take().unwrap(), child.stdin.take().unwrap());
+/// let mut p = StreamTube::new(stream).with_poll();
+/// ```
+#[derive(Debug)]
+pub struct Duplex<R, W> {
+    pub reader: R,
+    pub writer: W,
+}
+
+impl<R: Read, W: Write> Duplex<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Self {
+            reader: reader,
+            writer: writer,
+        }
+    }
+}
+
+impl<R: Read, W> Read for Duplex<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+/// the descriptor of the reader, which is what `StreamTube::with_poll` waits on
+#[cfg(unix)]
+impl<R: AsRawFd, W> AsRawFd for Duplex<R, W> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.reader.as_raw_fd()
+    }
+}
+
+impl<R, W: Write> Write for Duplex<R, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// sets the read timeout of a stream, like `TcpStream::set_read_timeout`
+pub type SetReadTimeout<S> = fn(&mut S, Timeout) -> io::Result<()>;
+
+/// tube over a stream given by the user
+///
+/// A plain stream can't be waited on, so a read blocks until the stream gives
+/// something and timeouts only apply between reads, which leaves `interactive`
+/// hanging after the first answer. Streams with a descriptor, like the pipes
+/// of a child, get proper timeouts with `with_poll` on Unix, streams which can
+/// time out by themselves, like sockets, with `with_read_timeout`. Sends have
+/// no such hook, they block until everything is written and their timeout is
+/// ignored.
+///
+/// A stream can't be closed halfway either: shutting down one direction makes
+/// it fail with `Eof`, shutting down both drops the stream.
+pub struct StreamTube<S> {
+    buf: Buffer,
+    stream: Option<S>,
+    set_read_timeout: Option<SetReadTimeout<S>>,
+    /// waited on before each read, set by `with_poll`
+    #[cfg(unix)]
+    read_fd: Option<RawFd>,
+    send_closed: bool,
+    recv_closed: bool,
+    newline: Vec<u8>,
+}
+
+impl<S: Read + Write> StreamTube<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            buf: Buffer::default(),
+            stream: Some(stream),
+            set_read_timeout: None,
+            #[cfg(unix)]
+            read_fd: None,
+            send_closed: false,
+            recv_closed: false,
+            newline: b"\n".to_vec(),
+        }
+    }
+
+    /// line terminator of `sendline` and `recvline`, `\n` by default
+    pub fn newline(mut self, new_newline: &[u8]) -> Self {
+        self.newline = new_newline.to_vec();
+        self
+    }
+
+    /// let reads time out through the stream itself
+    ///
+    /// ```ignore
+    /// StreamTube::new(stream).with_read_timeout(|s, timeout| s.set_read_timeout(timeout))
+    /// ```
+    pub fn with_read_timeout(mut self, set_read_timeout: SetReadTimeout<S>) -> Self {
+        self.set_read_timeout = Some(set_read_timeout);
+        self
+    }
+
+    /// the stream, unless dropped by `shutdown`
+    pub fn get_mut(&mut self) -> Option<&mut S> {
+        self.stream.as_mut()
+    }
+
+    /// the stream, unless dropped by `shutdown`, data still buffered is lost
+    pub fn into_inner(self) -> Option<S> {
+        self.stream
+    }
+
+    fn open_stream(&mut self, closed: bool) -> Result<&mut S, Error> {
+        match self.stream {
+            Some(ref mut stream) if !closed => Ok(stream),
+            _ => Err(Error::eof()),
+        }
+    }
+}
+
+/// wait until `fd` is readable, when `with_poll` set one
+#[cfg(unix)]
+fn wait_readable(fd: Option<RawFd>, timeout: Timeout) -> Result<(), Error> {
+    if let Some(fd) = fd {
+        if !poll::poll(&mut [poll::pollfd(fd, libc::POLLIN)], poll::deadline(timeout))? {
+            return Err(Error::timeout());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+impl<S: Read + Write + AsRawFd> StreamTube<S> {
+    /// wait on the descriptor of the stream before each read, so reads time
+    /// out like those of a `Process`
+    ///
+    /// The stream must not buffer reads itself, or what it holds is never seen.
+    pub fn with_poll(mut self) -> Self {
+        self.read_fd = self.stream.as_ref().map(|stream| stream.as_raw_fd());
+        self
+    }
+}
+
+impl<S: Read + Write> Newline for StreamTube<S> {
+    fn newline(&self) -> &[u8] {
+        &self.newline
+    }
+}
+
+impl<S: Read + Write> TubeRecv for StreamTube<S> {
+    fn mut_buffer(&mut self) -> &mut Buffer {
+        &mut self.buf
+    }
+
+    fn buffer(&self) -> &Buffer {
+        &self.buf
+    }
+
+    fn recv_once(&mut self, size: usize, timeout: Timeout) -> Result<Vec<u8>, Error> {
+        let mut content = vec![0u8; size];
+        let closed = self.recv_closed;
+        let set_read_timeout = self.set_read_timeout;
+        #[cfg(unix)]
+        let read_fd = self.read_fd;
+        let stream = self.open_stream(closed)?;
+        #[cfg(unix)]
+        wait_readable(read_fd, timeout)?;
+        if let Some(set_read_timeout) = set_read_timeout {
+            set_read_timeout(stream, timeout)?;
+        }
+        let n = loop {
+            match stream.read(&mut content) {
+                Ok(n) => break n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(stream_error(e)),
+            }
+        };
+        if n == 0 && size != 0 {
+            return Err(Error::eof());
+        }
+        content.truncate(n);
+        Ok(content)
+    }
+
+    #[cfg(unix)]
+    fn read_fds(&self) -> Option<Vec<RawFd>> {
+        let read_fd = self.read_fd?;
+        if self.recv_closed || self.stream.is_none() {
+            return Some(vec![]);
+        }
+        Some(vec![read_fd])
+    }
+}
+
+impl<S: Read + Write> TubeSend for StreamTube<S> {
+    #[action(content)]
+    fn send(&mut self, action: Action) -> Result<(), Error> {
+        let closed = self.send_closed;
+        let stream = self.open_stream(closed)?;
+        stream.write_all(&content).map_err(stream_error)?;
+        stream.flush().map_err(stream_error)
+    }
+}
+
+impl<S: Read + Write> TubeInternal for StreamTube<S> {
+    #[action(stdin, stdout)]
+    fn shutdown(&mut self, action: Action) -> Result<(), Error> {
+        if stdin {
+            if let Some(ref mut stream) = self.stream {
+                stream.flush()?;
+            }
+            self.send_closed = true;
+        }
+        if stdout {
+            self.recv_closed = true;
+        }
+        if self.send_closed && self.recv_closed {
+            self.stream = None;
+        }
+        Ok(())
+    }
+}
+
+impl<S: Read + Write> Tube for StreamTube<S> {}
+
+#[test]
+fn test_stream() {
+    use super::arg::ActionMethods;
+    use std::io::Cursor;
+
+    let stream = Duplex::new(Cursor::new(b"hello\r\nmenu> rest".to_vec()), vec![]);
+    let mut p = StreamTube::new(stream).newline(b"\r\n");
+    assert_eq!(p.recv_line().unwrap(), b"hello\r\n");
+    assert_eq!(p.recvuntil_str("> ").unwrap(), b"menu> ");
+    p.sendline_bytes("1").unwrap();
+    assert_eq!(p.get_mut().unwrap().writer, b"1\r\n");
+
+    let e = p.recv_n(8).unwrap_err();
+    assert!(e.is_closed());
+    assert_eq!(e.pending.unwrap(), b"rest");
+
+    p.close_send().unwrap();
+    assert!(p.send_bytes("2").unwrap_err().is_closed());
+    p.close().unwrap();
+    assert!(p.into_inner().is_none());
+}
+
+#[cfg(unix)]
+#[test]
+fn child_test_unix() {
+    use super::arg::ActionMethods;
+    use super::tube::TubeExt;
+    use std::process::{Command, Stdio};
+    use std::time::{Duration, Instant};
+
+    let mut child = Command::new("cat")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stream = Duplex::new(child.stdout.take().unwrap(), child.stdin.take().unwrap());
+    let mut p = StreamTube::new(stream).with_poll();
+    p.sendline_bytes("hi").unwrap();
+    assert_eq!(p.recv_line().unwrap(), b"hi\n");
+    // cat is still waiting for more, the pipe is polled instead of blocking
+    let start = Instant::now();
+    let e = p.with_timeout(Duration::from_millis(100)).recv_line().unwrap_err();
+    assert!(!e.is_closed() && e.is_incomplete());
+    assert!(start.elapsed() < Duration::from_secs(1));
+    // dropping the pipes lets cat see the end
+    p.close().unwrap();
+    assert!(child.wait().unwrap().success());
+}
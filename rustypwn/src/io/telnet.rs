@@ -0,0 +1,99 @@
+//! telnet option negotiation, refused on every option so the stream stays plain data
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+/// where the last read stopped, commands may be split across reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Data,
+    Iac,
+    /// waiting for the option of `WILL`, `WONT`, `DO` or `DONT`
+    Option(u8),
+    /// inside a subnegotiation, up to `IAC SE`
+    Sub,
+    SubIac,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Telnet {
+    state: State,
+}
+
+impl Default for Telnet {
+    fn default() -> Self {
+        Telnet { state: State::Data }
+    }
+}
+
+impl Telnet {
+    /// strip commands from `received`, returns the data along with the answers
+    /// to send back, `WONT` to every `DO` and `DONT` to every `WILL`
+    pub(crate) fn filter(&mut self, received: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut data = Vec::with_capacity(received.len());
+        let mut answers = vec![];
+        for &b in received {
+            self.state = match (self.state, b) {
+                (State::Data, IAC) => State::Iac,
+                (State::Data, b) => {
+                    data.push(b);
+                    State::Data
+                }
+                (State::Iac, IAC) => {
+                    data.push(IAC);
+                    State::Data
+                }
+                (State::Iac, WILL) | (State::Iac, WONT) | (State::Iac, DO) | (State::Iac, DONT) => State::Option(b),
+                (State::Iac, SB) => State::Sub,
+                // commands without an option, like NOP or GA
+                (State::Iac, _) => State::Data,
+                (State::Option(command), option) => {
+                    match command {
+                        DO => answers.extend_from_slice(&[IAC, WONT, option]),
+                        WILL => answers.extend_from_slice(&[IAC, DONT, option]),
+                        // already off on our side
+                        _ => {}
+                    }
+                    State::Data
+                }
+                (State::Sub, IAC) => State::SubIac,
+                (State::Sub, _) => State::Sub,
+                (State::SubIac, SE) => State::Data,
+                (State::SubIac, _) => State::Sub,
+            };
+        }
+        (data, answers)
+    }
+}
+
+/// double every `IAC` in data to send, so it isn't taken for a command
+pub(crate) fn escape(content: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(content.len());
+    for &b in content {
+        escaped.push(b);
+        if b == IAC {
+            escaped.push(IAC);
+        }
+    }
+    escaped
+}
+
+#[test]
+fn test_telnet() {
+    let mut telnet = Telnet::default();
+    let (data, answers) = telnet.filter(&[b'a', IAC, DO, 1, IAC, WILL, 3, IAC, IAC, b'b', IAC]);
+    assert_eq!(data, [b'a', IAC, b'b']);
+    assert_eq!(answers, [IAC, WONT, 1, IAC, DONT, 3]);
+
+    // the command goes on in the next read
+    let (data, answers) = telnet.filter(&[DONT, 1, IAC, SB, 24, 1, IAC, IAC, IAC, SE, b'c']);
+    assert_eq!(data, b"c");
+    assert!(answers.is_empty());
+
+    assert_eq!(escape(&[1, IAC, 2]), [1, IAC, IAC, 2]);
+}
@@ -1,83 +1,232 @@
-use super::arg::{Action, Timeout};
+use super::arg::{self, literal_pattern, Action, ActionMethods, Pattern, Timeout};
 use super::buffer::Buffer;
 use super::error::{Error, ErrorKind};
+use super::poll;
 use rustypwn_derive::action;
 use std::io;
 use std::io::{BufRead, Write};
-use std::ops::Drop;
-use std::time::{Duration, SystemTime};
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+use std::time::Duration;
 
-pub trait TubeInternal: Drop {
+/// line terminator of the line based actions, shared by both halves of a tube
+pub trait Newline {
+    fn newline(&self) -> &[u8] {
+        b"\n"
+    }
+}
+
+/// the loop of `expect`, reading until any of `patterns` is in the buffer
+fn expect_before<T: TubeRecv + ?Sized>(
+    tube: &mut T,
+    patterns: &[Pattern],
+    deadline: poll::Deadline,
+) -> Result<(usize, Vec<u8>), Error> {
+    let regexes = patterns.iter().map(|pattern| pattern.to_regex()).collect::<Vec<_>>();
+    let rescans = tube.mut_buffer().rescans(&regexes)?;
+    // what the last round searched without a match
+    let mut scanned = 0;
+    loop {
+        let res = tube.mut_buffer().get_until_any_after(&regexes, scanned)?;
+        match res {
+            Some(mat) => {
+                return Ok(mat);
+            }
+            _ => {
+                scanned = tube.buffer().len();
+                let left = poll::remaining(deadline);
+                if left == Some(Duration::from_secs(0)) {
+                    return Err(Error::timeout());
+                }
+                let mut v = tube.recv_once(0x1000, left)?;
+                tube.mut_buffer().append(&mut v);
+                // patterns searched from the start take everything there is
+                // first, so a huge leak isn't searched again for every read,
+                // up to double the size to keep a steady stream searched
+                if rescans {
+                    #[cfg(unix)]
+                    read_ready(tube, 2 * scanned)?;
+                }
+            }
+        }
+    }
+}
+
+/// bytes `act_all` sends ahead of the receives before them at most, on tubes
+/// whose answers can't be read in the meantime
+const PIPELINE: usize = 0x10000;
+
+/// read into the buffer while the tube has more right away, until it holds
+/// `limit` bytes
+#[cfg(unix)]
+fn read_ready<T: TubeRecv + ?Sized>(tube: &mut T, limit: usize) -> Result<(), Error> {
+    while tube.buffer().len() < limit {
+        // asked again each time, as descriptors close at the end
+        let fds = match tube.read_fds() {
+            Some(fds) if !fds.is_empty() => fds,
+            _ => break,
+        };
+        let mut pollfds = fds.iter().map(|&fd| poll::pollfd(fd, libc::POLLIN)).collect::<Vec<_>>();
+        if !poll::poll(&mut pollfds, poll::deadline(Some(Duration::from_secs(0))))? {
+            break;
+        }
+        match tube.recv_once(0x1000, None) {
+            Ok(mut v) => tube.mut_buffer().append(&mut v),
+            // the next round reads it again and tells
+            Err(_) => break,
+        }
+    }
+    Ok(())
+}
+
+/// the loop of `recv`, reading until the buffer can give `size` bytes
+fn recv_before<T: TubeRecv + ?Sized>(
+    tube: &mut T,
+    size: usize,
+    must: bool,
+    deadline: poll::Deadline,
+) -> Result<Vec<u8>, Error> {
+    loop {
+        if let Some(res) = tube.mut_buffer().get(size, must) {
+            return Ok(res);
+        }
+
+        let left = poll::remaining(deadline);
+        if left == Some(Duration::from_secs(0)) {
+            return Err(Error::timeout());
+        }
+        let mut put = tube.recv_once(size, left)?;
+        tube.mut_buffer().append(&mut put);
+    }
+}
+
+/// receiving half of a tube
+pub trait TubeRecv: Newline {
     /// internal buffer
     fn mut_buffer(&mut self) -> &mut Buffer;
     fn buffer(&self) -> &Buffer;
 
+    /// a single read of up to `size` bytes, the only thing a transport has to
+    /// give for the receives to work
     fn recv_once(&mut self, size: usize, timeout: Timeout) -> Result<Vec<u8>, Error>;
 
-    fn send(&mut self, action: Action) -> Result<(), Error>;
-    fn recv(&mut self, action: Action) -> Result<Vec<u8>, Error>;
-    fn shutdown(&mut self, action: Action) -> Result<(), Error>;
+    /// descriptors which turn readable once `recv_once` has something, so
+    /// several tubes can be waited on at once, `None` if the tube has none
+    ///
+    /// No descriptors at all means the tube is closed for reading.
+    #[cfg(unix)]
+    fn read_fds(&self) -> Option<Vec<RawFd>> {
+        None
+    }
 
-    #[action(timeout, content)]
-    fn sendline(&mut self, action: Action) -> Result<(), Error> {
-        let mut content = content;
-        content.push(b'\n');
-        self.send(Action::Send {
-            timeout: timeout,
-            content: content,
+    #[action(timeout, size, must)]
+    fn recv(&mut self, action: Action) -> Result<Vec<u8>, Error> {
+        recv_before(self, size, must, poll::deadline(timeout)).map_err(|e| {
+            let action = Action::Recv {
+                timeout: timeout,
+                size: size,
+                must: must,
+            };
+            e.with_pending(self.buffer().data(), action)
         })
     }
 
     #[action(timeout, pattern)]
     fn recvuntil(&mut self, action: Action) -> Result<Vec<u8>, Error> {
-        let now = SystemTime::now();
-        loop {
-            match timeout {
-                Some(timeout) => match now.elapsed() {
-                    Ok(elapsed) => {
-                        if elapsed > timeout {
-                            return Err(Error::from_kind(ErrorKind::Timeout));
-                        }
-                    }
-                    _ => {
-                        panic!("get elapsed time error, critical internal bug");
-                    }
-                },
-                _ => {}
-            }
+        let arg = Action::Expect {
+            timeout: timeout,
+            patterns: vec![Pattern::Regex(pattern.clone())],
+        };
+        self.expect(arg).map(|(_index, data)| data).map_err(|e| {
+            let action = Action::Recvuntil {
+                timeout: timeout,
+                pattern: pattern,
+            };
+            e.with_pending(self.buffer().data(), action)
+        })
+    }
 
-            let res = self.mut_buffer().get_until(&pattern)?;
-            match res {
-                Some(mat) => {
-                    return Ok(mat);
-                }
-                _ => {
-                    let mut v = self.recv_once(0x1000, timeout)?;
-                    self.mut_buffer().append(&mut v);
-                }
-            }
-        }
+    /// receive until the first of `patterns` matches, returns its index along
+    /// with everything received up to the end of the match
+    ///
+    /// The match ending first in the data wins, ties go to the earlier pattern.
+    #[action(timeout, patterns)]
+    fn expect(&mut self, action: Action) -> Result<(usize, Vec<u8>), Error> {
+        let res = expect_before(self, &patterns, poll::deadline(timeout));
+        res.map_err(|e| {
+            let action = Action::Expect {
+                timeout: timeout,
+                patterns: patterns,
+            };
+            e.with_pending(self.buffer().data(), action)
+        })
     }
 
     #[action(timeout)]
     fn recvline(&mut self, action: Action) -> Result<Vec<u8>, Error> {
         let arg = Action::Recvuntil {
             timeout: timeout,
-            pattern: "\n".to_string(),
+            pattern: literal_pattern(self.newline()),
         };
         self.recvuntil(arg)
+            .map_err(|e| e.with_pending(self.buffer().data(), Action::Recvline { timeout: timeout }))
+    }
+
+    /// lines keep their terminator, like `recvline`
+    #[action(timeout, count)]
+    fn recvlines(&mut self, action: Action) -> Result<Vec<Vec<u8>>, Error> {
+        let deadline = poll::deadline(timeout);
+        let mut lines = Vec::with_capacity(count);
+        for _ in 0..count {
+            let res = self.recvline(Action::Recvline {
+                timeout: poll::remaining(deadline),
+            });
+            match res {
+                Ok(line) => lines.push(line),
+                Err(e) => {
+                    // the lines already received stay pending, like partial ones do
+                    let received = lines.concat();
+                    self.mut_buffer().prepend(&received);
+                    let action = Action::Recvlines {
+                        timeout: timeout,
+                        count: count,
+                    };
+                    return Err(e.with_pending(self.buffer().data(), action));
+                }
+            }
+        }
+        Ok(lines)
     }
+}
+
+/// sending half of a tube
+pub trait TubeSend: Newline {
+    fn send(&mut self, action: Action) -> Result<(), Error>;
+
+    #[action(timeout, content)]
+    fn sendline(&mut self, action: Action) -> Result<(), Error> {
+        let mut content = content.into_owned();
+        content.extend_from_slice(self.newline());
+        self.send(Action::Send {
+            timeout: timeout,
+            content: content.into(),
+        })
+    }
+}
+
+pub trait TubeInternal: TubeRecv + TubeSend {
+    fn shutdown(&mut self, action: Action) -> Result<(), Error>;
 
     #[action]
     fn interactive(&mut self, action: Action) -> Result<(), Error> {
         let stdin = io::stdin();
         print!("$ ");
-        io::stdout().flush().unwrap();
+        io::stdout().flush()?;
         for line in stdin.lock().lines() {
-            let line = line.expect("unable to read line in interactive");
+            let line = line?;
             let arg = Action::Sendline {
                 timeout: None,
-                content: line.as_bytes().to_vec(),
+                content: line.as_bytes().into(),
             };
             self.sendline(arg)?;
             let arg = Action::Recv {
@@ -96,9 +245,25 @@ pub trait TubeInternal: Drop {
                     Err(e) => return Err(e),
                 }
             }
+            // show the lines of the tube the way lines look here
+            let newline = self.newline();
+            if newline != b"\n" && !newline.is_empty() {
+                let mut shown = Vec::with_capacity(recved.len());
+                let mut rest = recved.as_slice();
+                while !rest.is_empty() {
+                    if rest.starts_with(newline) {
+                        shown.push(b'\n');
+                        rest = &rest[newline.len()..];
+                    } else {
+                        shown.push(rest[0]);
+                        rest = &rest[1..];
+                    }
+                }
+                recved = shown;
+            }
             print!("{}", recved.iter().map(|c| *c as char).collect::<String>());
             print!("$ ");
-            io::stdout().flush().unwrap();
+            io::stdout().flush()?;
         }
 
         self.shutdown(Action::Shutdown {
@@ -109,17 +274,437 @@ pub trait TubeInternal: Drop {
     }
 }
 
+/// something a tube of type `T` can be told to do through `act`
+///
+/// Implement it for an enum deriving `ActionArg` to add custom actions, the
+/// variant structs generated by the derive act like the enum.
+pub trait Act<T: ?Sized> {
+    type Output;
+
+    fn act_on(self, tube: &mut T) -> Result<Self::Output, Error>;
+}
+
+impl<'a, T: Tube + ?Sized> Act<T> for Action<'a> {
+    type Output = Option<Vec<u8>>;
+
+    fn act_on(self, tube: &mut T) -> Result<Option<Vec<u8>>, Error> {
+        match self {
+            Action::Send { .. } => tube.send(self).map(|_res| None),
+            Action::Recv { .. } => tube.recv(self).map(Some),
+            Action::Sendline { .. } => tube.sendline(self).map(|_res| None),
+            Action::Recvline { .. } => tube.recvline(self).map(Some),
+            Action::Recvlines { .. } => tube.recvlines(self).map(|res| Some(res.concat())),
+            Action::Recvuntil { .. } => tube.recvuntil(self).map(Some),
+            // call `expect` directly for the index of the pattern
+            Action::Expect { .. } => tube.expect(self).map(|(_index, res)| Some(res)),
+            Action::Interactive => tube.interactive(self).map(|_res| None),
+            Action::Shutdown { .. } => tube.shutdown(self).map(|_res| None),
+        }
+    }
+}
+
+/// a variant struct run on a tube of type `T` by its `{Enum}Methods` method,
+/// giving what the tube gives for that variant rather than what `act` gives
+pub trait Run<T: ?Sized> {
+    type Output;
+
+    fn run_on(self, tube: &mut T) -> Result<Self::Output, Error>;
+}
+
+/// `Run` for variant structs of `Action`, straight to the method of the tube
+macro_rules! impl_run {
+    ($($variant:ty => $method:ident -> $output:ty,)*) => {
+        $(
+            impl<'a, T: Tube + ?Sized> Run<T> for $variant {
+                type Output = $output;
+
+                fn run_on(self, tube: &mut T) -> Result<$output, Error> {
+                    tube.$method(self.into())
+                }
+            }
+        )*
+    };
+}
+
+impl_run! {
+    arg::Send<'a> => send -> (),
+    arg::Recv => recv -> Vec<u8>,
+    arg::Sendline<'a> => sendline -> (),
+    arg::Recvline => recvline -> Vec<u8>,
+    arg::Recvlines => recvlines -> Vec<Vec<u8>>,
+    arg::Recvuntil => recvuntil -> Vec<u8>,
+    arg::Expect => expect -> (usize, Vec<u8>),
+    arg::Interactive => interactive -> (),
+    arg::Shutdown => shutdown -> (),
+}
+
+impl<T: Tube + ?Sized> ActionMethods for T {}
+
+/// a whole tube, usable as `Box<dyn Tube>`
+///
+/// The generic helpers like `act` come from `TubeExt`.
 pub trait Tube: TubeInternal {
-    fn act<T: Into<Action>>(&mut self, action: T) -> Result<Option<Vec<u8>>, Error> {
-        let action = action.into();
-        match action {
-            Action::Send { .. } => self.send(action).map(|_res| None),
-            Action::Recv { .. } => self.recv(action).map(|res| Some(res)),
-            Action::Sendline { .. } => self.sendline(action).map(|_res| None),
-            Action::Recvline { .. } => self.recvline(action).map(|res| Some(res)),
-            Action::Recvuntil { .. } => self.recvuntil(action).map(|res| Some(res)),
-            Action::Interactive => self.interactive(action).map(|_res| None),
-            Action::Shutdown { .. } => self.shutdown(action).map(|_res| None),
+    /// act on all of `actions` with sends pipelined ahead of receives
+    ///
+    /// Sends are written back to back without waiting for any answer, then the
+    /// receives collect the answers in order, so the whole batch costs a single
+    /// round trip. Only receives which know where their answer ends, lines,
+    /// patterns or a `must` size, are pipelined past: a plain `recv` would take
+    /// whatever arrived, answers to later sends included, so the sends after it
+    /// wait for it. Interactive and shutdown wait for the receives before them.
+    /// Results are in the order of `actions`, like those of `act`.
+    ///
+    /// Answers already there are read into the buffer before each send, so the
+    /// other side never blocks on them while we block on sending. Tubes which
+    /// can't be read that way, plain streams and everything off Unix, wait for
+    /// the pending receives after every 64 KiB sent instead.
+    fn act_all<'a>(&mut self, actions: Vec<Action<'a>>) -> Result<Vec<Option<Vec<u8>>>, Error> {
+        #[cfg(unix)]
+        let reads_ahead = self.read_fds().is_some();
+        #[cfg(not(unix))]
+        let reads_ahead = false;
+
+        let mut res = vec![None; actions.len()];
+        let mut pending: Vec<(usize, Action)> = vec![];
+        // bytes sent since the receives pending were queued
+        let mut ahead = 0;
+        for (i, action) in actions.into_iter().enumerate() {
+            let barrier = match action {
+                Action::Send { ref content, .. } | Action::Sendline { ref content, .. } => {
+                    #[cfg(unix)]
+                    {
+                        if reads_ahead && !pending.is_empty() {
+                            read_ready(self, usize::MAX)?;
+                        }
+                    }
+                    ahead += content.len();
+                    (!reads_ahead && ahead > PIPELINE)
+                        || pending.iter().any(|(_, pending)| matches!(pending, Action::Recv { must: false, .. }))
+                }
+                Action::Interactive | Action::Shutdown { .. } => true,
+                _ => false,
+            };
+            if barrier {
+                for (j, pending) in pending.drain(..) {
+                    res[j] = Action::act_on(pending, self)?;
+                }
+            }
+            if pending.is_empty() {
+                ahead = 0;
+            }
+            match action {
+                Action::Send { .. } | Action::Sendline { .. } | Action::Interactive | Action::Shutdown { .. } => {
+                    res[i] = action.act_on(self)?;
+                }
+                _ => pending.push((i, action)),
+            }
+        }
+        for (j, pending) in pending {
+            res[j] = Action::act_on(pending, self)?;
         }
+        Ok(res)
+    }
+}
+
+/// generic helpers of every tube, boxed ones included
+pub trait TubeExt: Tube {
+    fn act<A: Act<Self>>(&mut self, action: A) -> Result<A::Output, Error> {
+        action.act_on(self)
+    }
+
+    /// the tube with `timeout` for every action not having one set
+    ///
+    /// ```ignore
+    /// p.with_timeout(Duration::from_secs(1)).recvuntil_str("> ")?;
+    /// ```
+    fn with_timeout(&mut self, timeout: Duration) -> Timed<'_, Self> {
+        Timed {
+            tube: self,
+            timeout: timeout,
+        }
+    }
+}
+
+impl<T: Tube + ?Sized> TubeExt for T {}
+
+impl<T: Newline + ?Sized> Newline for Box<T> {
+    fn newline(&self) -> &[u8] {
+        (**self).newline()
     }
 }
+
+impl<T: TubeRecv + ?Sized> TubeRecv for Box<T> {
+    fn mut_buffer(&mut self) -> &mut Buffer {
+        (**self).mut_buffer()
+    }
+
+    fn buffer(&self) -> &Buffer {
+        (**self).buffer()
+    }
+
+    fn recv_once(&mut self, size: usize, timeout: Timeout) -> Result<Vec<u8>, Error> {
+        (**self).recv_once(size, timeout)
+    }
+
+    #[cfg(unix)]
+    fn read_fds(&self) -> Option<Vec<RawFd>> {
+        (**self).read_fds()
+    }
+
+    fn recv(&mut self, action: Action) -> Result<Vec<u8>, Error> {
+        (**self).recv(action)
+    }
+
+    fn recvuntil(&mut self, action: Action) -> Result<Vec<u8>, Error> {
+        (**self).recvuntil(action)
+    }
+
+    fn expect(&mut self, action: Action) -> Result<(usize, Vec<u8>), Error> {
+        (**self).expect(action)
+    }
+
+    fn recvline(&mut self, action: Action) -> Result<Vec<u8>, Error> {
+        (**self).recvline(action)
+    }
+
+    fn recvlines(&mut self, action: Action) -> Result<Vec<Vec<u8>>, Error> {
+        (**self).recvlines(action)
+    }
+}
+
+impl<T: TubeSend + ?Sized> TubeSend for Box<T> {
+    fn send(&mut self, action: Action) -> Result<(), Error> {
+        (**self).send(action)
+    }
+
+    fn sendline(&mut self, action: Action) -> Result<(), Error> {
+        (**self).sendline(action)
+    }
+}
+
+impl<T: TubeInternal + ?Sized> TubeInternal for Box<T> {
+    fn shutdown(&mut self, action: Action) -> Result<(), Error> {
+        (**self).shutdown(action)
+    }
+
+    fn interactive(&mut self, action: Action) -> Result<(), Error> {
+        (**self).interactive(action)
+    }
+}
+
+impl<T: Tube + ?Sized> Tube for Box<T> {
+    fn act_all<'a>(&mut self, actions: Vec<Action<'a>>) -> Result<Vec<Option<Vec<u8>>>, Error> {
+        (**self).act_all(actions)
+    }
+}
+
+/// tube lending a timeout to actions without one, made by `Tube::with_timeout`
+pub struct Timed<'t, T: ?Sized> {
+    tube: &'t mut T,
+    timeout: Duration,
+}
+
+impl<'t, T: ?Sized> Timed<'t, T> {
+    fn fill<'a>(&self, mut action: Action<'a>) -> Action<'a> {
+        if let Some(timeout) = action.timeout_mut() {
+            if timeout.is_none() {
+                *timeout = Some(self.timeout);
+            }
+        }
+        action
+    }
+}
+
+impl<'t, T: Newline + ?Sized> Newline for Timed<'t, T> {
+    fn newline(&self) -> &[u8] {
+        self.tube.newline()
+    }
+}
+
+impl<'t, T: TubeRecv + ?Sized> TubeRecv for Timed<'t, T> {
+    fn mut_buffer(&mut self) -> &mut Buffer {
+        self.tube.mut_buffer()
+    }
+
+    fn buffer(&self) -> &Buffer {
+        self.tube.buffer()
+    }
+
+    fn recv_once(&mut self, size: usize, timeout: Timeout) -> Result<Vec<u8>, Error> {
+        self.tube.recv_once(size, timeout.or(Some(self.timeout)))
+    }
+
+    #[cfg(unix)]
+    fn read_fds(&self) -> Option<Vec<RawFd>> {
+        self.tube.read_fds()
+    }
+
+    fn recv(&mut self, action: Action) -> Result<Vec<u8>, Error> {
+        let action = self.fill(action);
+        self.tube.recv(action)
+    }
+
+    fn recvuntil(&mut self, action: Action) -> Result<Vec<u8>, Error> {
+        let action = self.fill(action);
+        self.tube.recvuntil(action)
+    }
+
+    fn expect(&mut self, action: Action) -> Result<(usize, Vec<u8>), Error> {
+        let action = self.fill(action);
+        self.tube.expect(action)
+    }
+
+    fn recvline(&mut self, action: Action) -> Result<Vec<u8>, Error> {
+        let action = self.fill(action);
+        self.tube.recvline(action)
+    }
+
+    fn recvlines(&mut self, action: Action) -> Result<Vec<Vec<u8>>, Error> {
+        let action = self.fill(action);
+        self.tube.recvlines(action)
+    }
+}
+
+impl<'t, T: TubeSend + ?Sized> TubeSend for Timed<'t, T> {
+    fn send(&mut self, action: Action) -> Result<(), Error> {
+        let action = self.fill(action);
+        self.tube.send(action)
+    }
+
+    fn sendline(&mut self, action: Action) -> Result<(), Error> {
+        let action = self.fill(action);
+        self.tube.sendline(action)
+    }
+}
+
+impl<'t, T: TubeInternal + ?Sized> TubeInternal for Timed<'t, T> {
+    fn shutdown(&mut self, action: Action) -> Result<(), Error> {
+        self.tube.shutdown(action)
+    }
+
+    fn interactive(&mut self, action: Action) -> Result<(), Error> {
+        self.tube.interactive(action)
+    }
+}
+
+impl<'t, T: Tube + ?Sized> Tube for Timed<'t, T> {}
+
+#[test]
+fn test_expect_chunks() {
+    use super::arg::ActionMethods;
+    use super::stream::{Duplex, StreamTube};
+    use std::io::{Cursor, Read};
+    use std::time::Instant;
+
+    /// gives at most 64 bytes a read, like a slow connection
+    struct Chunks(Cursor<Vec<u8>>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = std::cmp::min(buf.len(), 64);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    let mut leak = vec![b'A'; 1 << 20];
+    leak.extend_from_slice(b"\n> ");
+    let stream = Duplex::new(Chunks(Cursor::new(leak)), vec![]);
+    let mut p = StreamTube::new(stream);
+    let start = Instant::now();
+    assert_eq!(p.recv_line().unwrap().len(), (1 << 20) + 1);
+    // searching the whole leak for every read would take minutes
+    assert!(start.elapsed() < Duration::from_secs(10));
+    assert_eq!(p.recvuntil_str("> ").unwrap(), b"> ");
+}
+
+#[cfg(unix)]
+#[test]
+fn custom_action_test_unix() {
+    use super::arg::{recvuntil, sendline};
+    use super::process::{Process, ProcessArg};
+    use rustypwn_derive::ActionArg;
+
+    #[derive(ActionArg)]
+    enum Menu {
+        Choose {
+            #[default = "0"]
+            choice: usize,
+            // no default needed, it falls back to `Default::default()`
+            fields: Vec<String>,
+        },
+    }
+
+    // a second derive in the same scope must not clash with the first
+    #[derive(ActionArg)]
+    enum Other {
+        Nothing,
+    }
+
+    impl<T: Tube> Act<T> for Menu {
+        type Output = Vec<u8>;
+
+        fn act_on(self, tube: &mut T) -> Result<Vec<u8>, Error> {
+            match self {
+                Menu::Choose { choice, fields } => {
+                    tube.act(sendline().content(choice.to_string()))?;
+                    for field in fields {
+                        tube.act(sendline().content(field))?;
+                    }
+                    tube.recvuntil(recvuntil().pattern("done".to_string()).into())
+                }
+            }
+        }
+    }
+
+    let mut p = Process::try_new(ProcessArg::default().argv(&["cat"])).unwrap();
+    let res = p.act(choose().choice(2).fields(vec!["done".to_string()])).unwrap();
+    assert_eq!(res, b"2\ndone");
+    let _ = Other::from(nothing());
+}
+
+#[cfg(unix)]
+#[test]
+fn methods_test_unix() {
+    use super::arg::{ActionMethods, Pattern};
+    use super::process::{Process, ProcessArg};
+
+    let mut p = Process::try_new(ProcessArg::default().argv(&["cat"])).unwrap();
+    p.sendline_bytes(b"menu\n> ").unwrap();
+    p.send_bytes("12345678").unwrap();
+    assert_eq!(p.recvuntil_str("> ").unwrap(), b"menu\n> ");
+    assert_eq!(p.recv_n(8).unwrap(), b"\n1234567");
+    p.sendline_bytes(vec![b'x']).unwrap();
+    assert_eq!(p.recv_line().unwrap(), b"8x\n");
+    p.sendline_bytes("Error").unwrap();
+    let patterns = vec![Pattern::literal("Success"), Pattern::literal("Error")];
+    assert_eq!(p.expect_any(patterns).unwrap(), (1, b"Error".to_vec()));
+    p.sendline_bytes("a\nb").unwrap();
+    assert_eq!(p.recv_lines(3).unwrap(), vec![b"\n".to_vec(), b"a\n".to_vec(), b"b\n".to_vec()]);
+
+    let start = std::time::Instant::now();
+    let e = p.with_timeout(Duration::from_millis(100)).recv_line().unwrap_err();
+    assert_eq!(e.kind, ErrorKind::Timeout);
+    assert!(start.elapsed() < Duration::from_secs(1));
+    p.close_send().unwrap();
+    assert!(p.recv_some(8).unwrap_err().is_closed());
+}
+
+#[cfg(unix)]
+#[test]
+fn boxed_test_unix() {
+    use super::arg::{recvline, sendline, ActionMethods};
+    use super::process::{Process, ProcessArg};
+
+    fn connect() -> Result<Box<dyn Tube>, Error> {
+        Ok(Box::new(Process::try_new(ProcessArg::default().argv(&["cat"]))?))
+    }
+
+    let mut p = connect().unwrap();
+    p.act(sendline().content("hi")).unwrap();
+    assert_eq!(p.act(recvline()).unwrap().unwrap(), b"hi\n");
+    p.sendline_bytes("there").unwrap();
+    let tube: &mut dyn Tube = &mut *p;
+    assert_eq!(tube.with_timeout(Duration::from_secs(1)).recv_line().unwrap(), b"there\n");
+    let res = tube.act_all(vec![sendline().content("x").into(), recvline().into()]).unwrap();
+    assert_eq!(res, vec![None, Some(b"x\n".to_vec())]);
+}
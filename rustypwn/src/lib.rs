@@ -1,2 +1,7 @@
-#![feature(custom_attribute, bind_by_move_pattern_guards, try_from)]
+// fields are spelled out as `timeout: timeout` throughout
+#![allow(clippy::redundant_field_names)]
+
+// lets the derive macros name this crate the same way from inside and outside
+extern crate self as rustypwn;
+
 pub mod io;
@@ -1,12 +1,12 @@
-#![feature(proc_macro_diagnostic, box_syntax, fmt_internals)]
 #![recursion_limit = "128"]
 extern crate proc_macro;
 
-use proc_macro2::{Span, TokenStream};
+use proc_macro2::{Span, TokenStream, TokenTree};
 use quote::quote;
 use syn::spanned::Spanned;
 use syn::{
-    parse_macro_input, Data, DataEnum, DeriveInput, Ident, Lit, Meta, MetaNameValue, Variant,
+    parse_macro_input, Data, DeriveInput, GenericParam, Generics, Ident, Lit, Meta,
+    MetaNameValue, NestedMeta, Variant, Visibility,
     parse::Parse, parse::ParseStream,
 };
 
@@ -57,6 +57,17 @@ use syn::{
 ///     }
 /// }
 ///
+/// impl TryFrom<Action> for Send {
+///     type Error = Error;
+///
+///     fn try_from(action: Action) -> Result<Self, Error> {
+///         match action {
+///             Action::Send { timeout, content } => Ok(Send { timeout, content }),
+///             _ => Err(Error::from_kind(ErrorKind::IncorrectAction)),
+///         }
+///     }
+/// }
+///
 /// impl Default for Send {
 ///     fn default() -> Self {
 ///         Self {
@@ -87,103 +98,282 @@ use syn::{
 ///     Send::default()
 /// }
 /// ```
-#[proc_macro_derive(ActionArg, attributes(default))]
+///
+/// Fields marked `#[into]` get a builder taking anything `Into` the field type,
+/// and lifetimes of the enum are carried over to the variants using them:
+///
+/// ```ignore
+/// #[derive(ActionArg)]
+/// pub enum Action<'a> {
+///     Send {
+///         #[default = "Content::default()"]
+///         #[into]
+///         content: Content<'a>,
+///     }
+/// }
+/// ```
+///
+/// gives
+///
+/// ```ignore
+/// pub struct Send<'a> {
+///     pub content: Content<'a>,
+/// }
+///
+/// impl<'a> Send<'a> {
+///     pub fn content<T: Into<Content<'a>>>(mut self, content: T) -> Self {
+///         self.content = content.into();
+///         self
+///     }
+/// }
+/// ```
+///
+/// Every variant struct also converts back with `TryFrom`, failing with
+/// `IncorrectAction` on other variants, and implements `Act` whenever the enum
+/// does, giving what the enum gives, so custom action enums run through
+/// `TubeExt::act` just like `Action`.
+///
+/// `#[method(name, field, ..., field = "VALUE")]` on a variant adds a method
+/// to the `{Enum}Methods` trait, usable on every type the variant struct runs
+/// on through `Run`, which gives the method its own output. The trait comes
+/// without implementations, they are up to the crate of the enum, and isn't
+/// generated without any method. A method takes the listed fields in order,
+/// sets the assigned ones and leaves the rest at their defaults:
+///
+/// ```ignore
+/// #[derive(ActionArg)]
+/// pub enum Action {
+///     #[method(recv_n, size, must = "true")]
+///     Recv { .. }
+/// }
+/// ```
+///
+/// gives
+///
+/// ```ignore
+/// pub trait ActionMethods {
+///     fn recv_n(&mut self, size: usize) -> Result<<Recv as Run<Self>>::Output, Error>
+///     where
+///         Recv: Run<Self>,
+///     {
+///         let mut action = Recv::default();
+///         action.size = size;
+///         action.must = true;
+///         action.run_on(self)
+///     }
+/// }
+///
+/// // in the crate of the enum
+/// impl<T: Tube + ?Sized> ActionMethods for T {}
+/// ```
+#[proc_macro_derive(ActionArg, attributes(default, into, method))]
 pub fn arction_arg(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
-    let data = match input.data {
+    let output = match impl_action_arg(&input) {
+        Ok(output) => output,
+        Err(e) => e.to_compile_error(),
+    };
+    proc_macro::TokenStream::from(output)
+}
+
+fn impl_action_arg(input: &DeriveInput) -> syn::Result<TokenStream> {
+    let data = match &input.data {
         Data::Enum(data) => data,
         _ => {
-            input
-                .span()
-                .unwrap()
-                .error("ActionArg cannot be used on item other than enum")
-                .emit();
-
-            panic!("ActionArg cannot be used on item other than enum")
+            return Err(syn::Error::new(
+                input.ident.span(),
+                "ActionArg cannot be used on item other than enum",
+            ))
         }
     };
 
-    let variants_impl = impl_variants(&input.ident, &data);
+    let mut variants_impl = Vec::new();
+    let mut methods = Vec::new();
+    for variant in data.variants.iter() {
+        variants_impl.push(impl_single_variant(&input.vis, &input.ident, &input.generics, variant)?);
+        methods.extend(impl_variant_methods(&input.generics, variant)?);
+    }
 
-    let output = quote! {
-        use std::convert::TryFrom;
+    let vis = &input.vis;
+    let methods_trait = Ident::new(&format!("{}Methods", input.ident), Span::call_site());
+    let doc = format!("direct methods running the variants of `{}`", input.ident);
+    let methods_impl = if methods.is_empty() {
+        quote! {}
+    } else {
+        quote! {
+            #[doc = #doc]
+            #vis trait #methods_trait {
+                #(#methods)*
+            }
+        }
+    };
 
+    Ok(quote! {
         #(#variants_impl)*
-    };
 
-    proc_macro::TokenStream::from(output)
+        #methods_impl
+    })
 }
 
-fn impl_variants(enum_name: &Ident, data: &DataEnum) -> Vec<TokenStream> {
-    let mut impls = Vec::new();
-    for variant in data.variants.iter() {
-        impls.push(impl_single_variant(enum_name, &variant));
+/// whether `ident` shows up in `tokens`, as a type or after `'` as a lifetime
+fn mentions(tokens: TokenStream, ident: &Ident, lifetime: bool) -> bool {
+    let mut after_quote = false;
+    for token in tokens {
+        match &token {
+            TokenTree::Group(group) if mentions(group.stream(), ident, lifetime) => return true,
+            TokenTree::Ident(found) if found == ident && after_quote == lifetime => {
+                return true;
+            }
+            _ => {}
+        }
+        after_quote = match &token {
+            TokenTree::Punct(punct) => punct.as_char() == '\'',
+            _ => false,
+        };
+    }
+    false
+}
+
+/// generic parameters of the enum which the variant uses in its fields,
+/// since a struct can't have unused ones
+fn variant_generics(generics: &Generics, variant: &Variant) -> Generics {
+    let fields = variant.fields.iter().map(|field| &field.ty);
+    let tokens = quote! { #(#fields)* };
+    let mut used = generics.clone();
+    used.where_clause = None;
+    used.params = generics
+        .params
+        .iter()
+        .filter(|param| match param {
+            GenericParam::Lifetime(def) => mentions(tokens.clone(), &def.lifetime.ident, true),
+            GenericParam::Type(ty) => mentions(tokens.clone(), &ty.ident, false),
+            GenericParam::Const(c) => mentions(tokens.clone(), &c.ident, false),
+        })
+        .cloned()
+        .collect();
+    used
+}
+
+fn param_ident(param: &GenericParam) -> &Ident {
+    match param {
+        GenericParam::Lifetime(def) => &def.lifetime.ident,
+        GenericParam::Type(ty) => &ty.ident,
+        GenericParam::Const(c) => &c.ident,
+    }
+}
+
+/// the enum as seen from a variant struct, with lifetimes the struct doesn't
+/// have being `'static`, or `None` if the struct lacks a type parameter
+fn enum_from_variant(enum_name: &Ident, generics: &Generics, struct_generics: &Generics) -> Option<TokenStream> {
+    let mut args = Vec::new();
+    for param in generics.params.iter() {
+        let used = struct_generics
+            .params
+            .iter()
+            .any(|used| param_ident(used) == param_ident(param));
+        match param {
+            GenericParam::Lifetime(def) if used => {
+                let lifetime = &def.lifetime;
+                args.push(quote! { #lifetime });
+            }
+            GenericParam::Lifetime(_) => args.push(quote! { 'static }),
+            GenericParam::Type(ty) if used => {
+                let ident = &ty.ident;
+                args.push(quote! { #ident });
+            }
+            GenericParam::Const(c) if used => {
+                let ident = &c.ident;
+                args.push(quote! { #ident });
+            }
+            _ => return None,
+        }
+    }
+    if args.is_empty() {
+        Some(quote! { #enum_name })
+    } else {
+        Some(quote! { #enum_name<#(#args),*> })
     }
-    impls
 }
 
-fn impl_single_variant(enum_name: &Ident, variant: &Variant) -> TokenStream {
+/// generated items are as visible as the enum
+fn impl_single_variant(
+    vis: &Visibility,
+    enum_name: &Ident,
+    generics: &Generics,
+    variant: &Variant,
+) -> syn::Result<TokenStream> {
     let name = &variant.ident;
+    let (enum_impl_generics, enum_ty_generics, enum_where_clause) = generics.split_for_impl();
+    let struct_generics = variant_generics(generics, variant);
+    let (impl_generics, ty_generics, _) = struct_generics.split_for_impl();
 
     let mut fields = Vec::new();
     let mut fields_default = Vec::new();
     let mut fields_methods = Vec::new();
     let mut fields_setups = Vec::new();
+    let mut field_names = Vec::new();
 
     for field in variant.fields.iter() {
         let field_name = match &field.ident {
             Some(ident) => ident,
             None => {
-                field
-                    .span()
-                    .unwrap()
-                    .error("ActionArg all enum variants' fields must be named")
-                    .emit();
-
-                panic!("incorrect enum")
+                return Err(syn::Error::new(
+                    field.span(),
+                    "ActionArg all enum variants' fields must be named",
+                ))
             }
         };
+        field_names.push(field_name);
         fields_setups.push(quote! {
             #field_name: action.#field_name,
         });
 
         let ty = &field.ty;
         fields.push(quote! {
-            pub #field_name: #ty,
+            #vis #field_name: #ty,
         });
 
-        let default_attr = &field.attrs[0];
-        let default_val = match default_attr.parse_meta().unwrap() {
-            Meta::NameValue(MetaNameValue {
-                lit: Lit::Str(lit_str),
-                ..
-            }) => lit_str.parse::<TokenStream>().unwrap(),
-            _ => {
-                default_attr
-                    .span()
-                    .unwrap()
-                    .error("field default should be formed as #[default = \"VALUE\"]")
-                    .emit();
-
-                panic!("incorrect field default value")
-            }
+        let default_val = match field.attrs.iter().find(|attr| attr.path.is_ident("default")) {
+            Some(attr) => match attr.parse_meta() {
+                Ok(Meta::NameValue(MetaNameValue {
+                    lit: Lit::Str(lit_str),
+                    ..
+                })) => lit_str.parse::<TokenStream>()?,
+                _ => {
+                    return Err(syn::Error::new_spanned(
+                        attr,
+                        "field default should be formed as #[default = \"VALUE\"]",
+                    ))
+                }
+            },
+            None => quote! { ::std::default::Default::default() },
         };
 
         fields_default.push(quote! {
             #field_name: #default_val,
         });
 
-        fields_methods.push(quote! {
-            pub fn #field_name(mut self, #field_name: #ty) -> Self {
-                self.#field_name = #field_name;
-                self
-            }
-        });
+        let into = field.attrs.iter().any(|attr| attr.path.is_ident("into"));
+        if into {
+            fields_methods.push(quote! {
+                #vis fn #field_name<T: ::std::convert::Into<#ty>>(mut self, #field_name: T) -> Self {
+                    self.#field_name = #field_name.into();
+                    self
+                }
+            });
+        } else {
+            fields_methods.push(quote! {
+                #vis fn #field_name(mut self, #field_name: #ty) -> Self {
+                    self.#field_name = #field_name;
+                    self
+                }
+            });
+        }
     }
 
     let from_impl = quote! {
-        impl From<#name> for #enum_name {
-            fn from(action: #name) -> Self {
+        impl #enum_impl_generics ::std::convert::From<#name #ty_generics> for #enum_name #enum_ty_generics #enum_where_clause {
+            fn from(action: #name #ty_generics) -> Self {
                 #enum_name::#name {
                     #(#fields_setups)*
                 }
@@ -191,20 +381,69 @@ fn impl_single_variant(enum_name: &Ident, variant: &Variant) -> TokenStream {
         }
     };
 
+    let field_names = &field_names;
+    let try_from_impl = quote! {
+        impl #enum_impl_generics ::std::convert::TryFrom<#enum_name #enum_ty_generics> for #name #ty_generics #enum_where_clause {
+            type Error = ::rustypwn::io::error::Error;
+
+            #[allow(unreachable_patterns)]
+            fn try_from(action: #enum_name #enum_ty_generics) -> ::std::result::Result<Self, Self::Error> {
+                match action {
+                    #enum_name::#name {
+                        #(#field_names),*
+                    } => ::std::result::Result::Ok(#name {
+                        #(#field_names),*
+                    }),
+                    _ => ::std::result::Result::Err(::rustypwn::io::error::Error::from_kind(
+                        ::rustypwn::io::error::ErrorKind::IncorrectAction,
+                    )),
+                }
+            }
+        }
+    };
+
+    // a variant struct acts like its enum, so `act` takes either
+    let act_impl = match enum_from_variant(enum_name, generics, &struct_generics) {
+        Some(enum_ty) => {
+            let mut act_generics = struct_generics.clone();
+            act_generics.params.push(syn::parse_quote!(__T: ?Sized));
+            let (act_impl_generics, _, _) = act_generics.split_for_impl();
+            let predicates = match enum_where_clause {
+                Some(where_clause) => where_clause.predicates.iter().collect::<Vec<_>>(),
+                None => Vec::new(),
+            };
+            quote! {
+                impl #act_impl_generics ::rustypwn::io::tube::Act<__T> for #name #ty_generics
+                where
+                    #enum_ty: ::rustypwn::io::tube::Act<__T>,
+                    #(#predicates,)*
+                {
+                    type Output = <#enum_ty as ::rustypwn::io::tube::Act<__T>>::Output;
+
+                    fn act_on(self, tube: &mut __T) -> ::std::result::Result<Self::Output, ::rustypwn::io::error::Error> {
+                        let action = <#enum_ty as ::std::convert::From<Self>>::from(self);
+                        <#enum_ty as ::rustypwn::io::tube::Act<__T>>::act_on(action, tube)
+                    }
+                }
+            }
+        }
+        None => quote! {},
+    };
+
     let name_lower = Ident::new(&name.to_string().to_ascii_lowercase(), Span::call_site());
 
     let helper_func_impl = quote! {
-        pub fn #name_lower() -> #name {
-            #name::default()
+        #vis fn #name_lower #impl_generics() -> #name #ty_generics {
+            <#name #ty_generics as ::std::default::Default>::default()
         }
     };
 
-    quote! {
-        pub struct #name {
+    Ok(quote! {
+        #vis struct #name #impl_generics {
             #(#fields)*
         }
 
-        impl Default for #name {
+        impl #impl_generics ::std::default::Default for #name #ty_generics {
             fn default() -> Self {
                 Self {
                     #(#fields_default)*
@@ -214,9 +453,13 @@ fn impl_single_variant(enum_name: &Ident, variant: &Variant) -> TokenStream {
 
         #from_impl
 
-        impl #name {
-            pub fn new() -> Self {
-                Self::default()
+        #try_from_impl
+
+        #act_impl
+
+        impl #impl_generics #name #ty_generics {
+            #vis fn new() -> Self {
+                <Self as ::std::default::Default>::default()
             }
 
             #(#fields_methods)*
@@ -224,7 +467,101 @@ fn impl_single_variant(enum_name: &Ident, variant: &Variant) -> TokenStream {
 
 
         #helper_func_impl
+    })
+}
+
+/// methods of the `#[method(...)]` attributes of `variant`
+fn impl_variant_methods(generics: &Generics, variant: &Variant) -> syn::Result<Vec<TokenStream>> {
+    let name = &variant.ident;
+    let struct_generics = variant_generics(generics, variant);
+    let (_, ty_generics, _) = struct_generics.split_for_impl();
+
+    let mut methods = Vec::new();
+    for attr in variant.attrs.iter().filter(|attr| attr.path.is_ident("method")) {
+        let nested = match attr.parse_meta() {
+            Ok(Meta::List(list)) => list.nested,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    attr,
+                    "method should be formed as #[method(name, field, field = \"VALUE\")]",
+                ))
+            }
+        };
+        let find_field = |ident: &Ident| match variant.fields.iter().find(|field| field.ident.as_ref() == Some(ident)) {
+            Some(field) => Ok(field),
+            None => Err(syn::Error::new(ident.span(), format!("no field `{}` in `{}`", ident, variant.ident))),
+        };
+
+        let mut method = None;
+        let mut params = Vec::new();
+        let mut args = Vec::new();
+        let mut setups = Vec::new();
+        let mut method_generics = struct_generics.clone();
+        for meta in nested.iter() {
+            match meta {
+                NestedMeta::Meta(Meta::Word(ident)) if method.is_none() => method = Some(ident.clone()),
+                NestedMeta::Meta(Meta::Word(ident)) => {
+                    let field = find_field(ident)?;
+                    let ty = &field.ty;
+                    if field.attrs.iter().any(|attr| attr.path.is_ident("into")) {
+                        let param = Ident::new(&format!("__{}", ident.to_string().to_uppercase()), Span::call_site());
+                        method_generics
+                            .params
+                            .push(syn::parse_quote!(#param: ::std::convert::Into<#ty>));
+                        params.push(quote! { #ident: #param });
+                        setups.push(quote! { action.#ident = #ident.into(); });
+                    } else {
+                        params.push(quote! { #ident: #ty });
+                        setups.push(quote! { action.#ident = #ident; });
+                    }
+                    args.push(ident.clone());
+                }
+                NestedMeta::Meta(Meta::NameValue(MetaNameValue {
+                    ident,
+                    lit: Lit::Str(lit_str),
+                    ..
+                })) if method.is_some() => {
+                    find_field(ident)?;
+                    let value = lit_str.parse::<TokenStream>()?;
+                    setups.push(quote! { action.#ident = #value; });
+                }
+                _ => {
+                    return Err(syn::Error::new_spanned(
+                        meta,
+                        "method should be formed as #[method(name, field, field = \"VALUE\")]",
+                    ))
+                }
+            }
+        }
+        let method = match method {
+            Some(method) => method,
+            None => return Err(syn::Error::new_spanned(attr, "method needs a name")),
+        };
+
+        let (method_generics, _, _) = method_generics.split_for_impl();
+        let doc = if args.is_empty() {
+            format!("run `{}`", name)
+        } else {
+            let args = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+            format!("run `{}` with `{}` given", name, args.join("`, `"))
+        };
+        methods.push(quote! {
+            #[doc = #doc]
+            fn #method #method_generics(&mut self, #(#params),*) -> ::std::result::Result<
+                <#name #ty_generics as ::rustypwn::io::tube::Run<Self>>::Output,
+                ::rustypwn::io::error::Error,
+            >
+            where
+                #name #ty_generics: ::rustypwn::io::tube::Run<Self>,
+            {
+                #[allow(unused_mut)]
+                let mut action = <#name #ty_generics as ::std::default::Default>::default();
+                #(#setups)*
+                <#name #ty_generics as ::rustypwn::io::tube::Run<Self>>::run_on(action, self)
+            }
+        });
     }
+    Ok(methods)
 }
 
 struct CommaSepIdents {
@@ -261,15 +598,13 @@ impl Parse for CommaSepIdents {
 /// impl TubeInternal for Process {
 /// //...
 /// fn sendline(&mut self, action: Action) -> Result<(), Error> {
-///     match action {
-///         Action::Sendline {
-///             timeout,
-///             content
-///         } => {
-///             // code
-///         },
-///         _ => panic!("incorrect action, internal bug"),
-///     }
+///     // `IncorrectAction` is returned for any other variant
+///     let Sendline {
+///         timeout,
+///         content,
+///         ..
+///     } = Sendline::try_from(action)?;
+///     // code
 /// }
 /// //...
 /// }
@@ -281,33 +616,69 @@ pub fn action(
 ) -> proc_macro::TokenStream {
     let input = parse_macro_input!(input as syn::ItemFn);
     let args = parse_macro_input!(attr as CommaSepIdents).idents;
+    let output = match impl_action(input, args) {
+        Ok(output) => output,
+        Err(e) => e.to_compile_error(),
+    };
+    proc_macro::TokenStream::from(output)
+}
 
+/// whether `ty` names `Action`, whatever its path and lifetime
+fn is_action_type(ty: &syn::Type) -> bool {
+    match ty {
+        syn::Type::Path(path) => match path.path.segments.last() {
+            Some(segment) => segment.value().ident == "Action",
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+fn impl_action(
+    input: syn::ItemFn,
+    args: syn::punctuated::Punctuated<Ident, syn::token::Comma>,
+) -> syn::Result<TokenStream> {
     let vis = input.vis;
     let ident = input.ident;
     let mut matching = ident.to_string().chars().collect::<Vec<_>>();
     matching[0] = matching[0].to_uppercase().collect::<Vec<_>>()[0];
-    let matching = Ident::new(&matching.iter().collect::<String>(), Span::call_site());
+    let matching = Ident::new(&matching.iter().collect::<String>(), ident.span());
     let decl = input.decl.inputs;
     let ret = input.decl.output;
-    let action_arg_name = match decl.iter().collect::<Vec<_>>()[1] {
-        syn::FnArg::Captured(captured) => &captured.pat,
-        _ => panic!("unable to get action arg"),
+    let action_arg = decl.iter().find_map(|arg| match arg {
+        syn::FnArg::Captured(captured) if is_action_type(&captured.ty) => Some(captured),
+        _ => None,
+    });
+    let action_arg_name = match action_arg {
+        Some(syn::ArgCaptured {
+            pat: syn::Pat::Ident(pat),
+            ..
+        }) => &pat.ident,
+        Some(captured) => {
+            return Err(syn::Error::new_spanned(
+                &captured.pat,
+                "the action argument must be bound to a plain name",
+            ))
+        }
+        None => {
+            return Err(syn::Error::new_spanned(
+                &decl,
+                "action function must take an argument of type `Action`",
+            ))
+        }
     };
     let block = input.block;
     let generics = input.decl.generics;
+    let where_clause = &generics.where_clause;
     let variadic = input.decl.variadic;
 
-    let gen = quote! {
-        #vis fn #ident #generics(#decl #variadic) #ret {
-            match #action_arg_name {
-                Action::#matching {
-                    #(#args),*
-                } => {
-                    #block
-                },
-                _ => panic!("incorrect action, internal bug"),
-            }
+    Ok(quote! {
+        #vis fn #ident #generics(#decl #variadic) #ret #where_clause {
+            let ::rustypwn::io::arg::#matching {
+                #(#args,)*
+                ..
+            } = <::rustypwn::io::arg::#matching as ::std::convert::TryFrom<_>>::try_from(#action_arg_name)?;
+            #block
         }
-    };
-    proc_macro::TokenStream::from(gen)
+    })
 }
@@ -0,0 +1,11 @@
+use rustypwn_derive::ActionArg;
+
+#[derive(ActionArg)]
+pub enum Action {
+    Recv {
+        #[default(0x1000)]
+        size: usize,
+    },
+}
+
+fn main() {}
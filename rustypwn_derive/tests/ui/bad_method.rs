@@ -0,0 +1,12 @@
+use rustypwn_derive::ActionArg;
+
+#[derive(ActionArg)]
+pub enum Action {
+    #[method(recv_n, length)]
+    Recv {
+        #[default = "0x1000"]
+        size: usize,
+    },
+}
+
+fn main() {}
@@ -0,0 +1,8 @@
+use rustypwn_derive::action;
+
+#[action(timeout)]
+fn recv(size: usize) -> Result<Vec<u8>, ()> {
+    Ok(vec![0; size])
+}
+
+fn main() {}
@@ -0,0 +1,8 @@
+use rustypwn_derive::ActionArg;
+
+#[derive(ActionArg)]
+pub struct Send {
+    content: Vec<u8>,
+}
+
+fn main() {}
@@ -0,0 +1,8 @@
+use rustypwn_derive::ActionArg;
+
+#[derive(ActionArg)]
+pub enum Action {
+    Send(Vec<u8>),
+}
+
+fn main() {}